@@ -0,0 +1,202 @@
+use point::Point;
+use rendering::{Intersectable, TextureCoords};
+use scene::{load_texture, write_texture, Material};
+use texture::Texture;
+use vector::Vector3;
+
+fn default_strength() -> f32 {
+    1.0
+}
+
+//A tangent-space normal map, with green pointing up the image as in OpenGL.
+#[derive(Serialize, Deserialize, Clone)]
+pub struct NormalMap {
+    #[serde(deserialize_with = "load_texture", serialize_with = "write_texture")]
+    pub texture: Texture,
+    //Scales the tilt the map gives; 0 leaves the surface flat.
+    #[serde(default = "default_strength")]
+    pub strength: f32,
+}
+
+//A greyscale height map: black lies on the surface, white `height` above it.
+#[derive(Serialize, Deserialize, Clone)]
+pub struct BumpMap {
+    #[serde(deserialize_with = "load_texture", serialize_with = "write_texture")]
+    pub texture: Texture,
+    pub height: f32,
+}
+
+//The normal used for shading: the geometric one, tilted by the material's normal and bump maps.
+pub fn shading_normal(
+    material: &Material,
+    surface: &dyn Intersectable,
+    hit_point: &Point,
+    normal: Vector3,
+) -> Vector3 {
+    if material.normal_map.is_none() && material.bump_map.is_none() {
+        return normal;
+    }
+    let texture_coords = surface.texture_coords(hit_point);
+    let (dp_du, dp_dv) = surface.tangent_frame(hit_point);
+    //The frame follows the shading normal, which on smoothed meshes isn't the face's.
+    let dp_du = dp_du - normal * normal.dot(&dp_du);
+    let dp_dv = dp_dv - normal * normal.dot(&dp_dv);
+
+    let mut shading = normal;
+    if let Some(ref map) = material.normal_map {
+        shading = apply_normal_map(map, &texture_coords, shading, dp_du, dp_dv);
+    }
+    if let Some(ref map) = material.bump_map {
+        shading = apply_bump_map(map, &texture_coords, shading, dp_du, dp_dv);
+    }
+    shading
+}
+
+fn apply_normal_map(
+    map: &NormalMap,
+    texture_coords: &TextureCoords,
+    normal: Vector3,
+    dp_du: Vector3,
+    dp_dv: Vector3,
+) -> Vector3 {
+    if dp_du.length() < 1e-12 || dp_dv.length() < 1e-12 {
+        return normal;
+    }
+    let tangent = dp_du.normalise();
+    let bitangent = dp_dv - tangent * tangent.dot(&dp_dv);
+    let bitangent = if bitangent.length() > 1e-12 {
+        bitangent.normalise()
+    } else {
+        normal.cross(&tangent)
+    };
+    let texel = map.texture.color(texture_coords, 0.0);
+    let x = (texel.red * 2.0 - 1.0) * map.strength;
+    let y = (texel.green * 2.0 - 1.0) * map.strength;
+    let z = texel.blue * 2.0 - 1.0;
+    //Texture y runs down the image, against the map's green.
+    let mapped = tangent * x as f64 - bitangent * y as f64 + normal * z.max(0.0) as f64;
+    if mapped.length() < 1e-12 {
+        normal
+    } else {
+        mapped.normalise()
+    }
+}
+
+//Blinn's bump mapping: tilting the normal against the slope of the height field across the
+//surface, found from its slope in texture space and how far the surface moves per unit of each
+//texture coordinate.
+fn apply_bump_map(
+    map: &BumpMap,
+    texture_coords: &TextureCoords,
+    normal: Vector3,
+    dp_du: Vector3,
+    dp_dv: Vector3,
+) -> Vector3 {
+    let (dh_du, dh_dv) = map.texture.gradient(texture_coords);
+    let mut slope = Vector3::zero();
+    if dp_du.dot(&dp_du) > 1e-24 {
+        slope = slope + dp_du * (dh_du as f64 / dp_du.dot(&dp_du));
+    }
+    if dp_dv.dot(&dp_dv) > 1e-24 {
+        slope = slope + dp_dv * (dh_dv as f64 / dp_dv.dot(&dp_dv));
+    }
+    (normal - slope * map.height as f64).normalise()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use framebuffer::Framebuffer;
+    use scene::{Color, Plane, Sphere};
+    use serde_json;
+
+    fn material() -> Material {
+        serde_json::from_str(
+            r#"{"coloration": {"Color": {"red": 1.0, "green": 1.0, "blue": 1.0}},
+                "albedo": 0.18, "surface": "Diffuse"}"#,
+        )
+        .unwrap()
+    }
+
+    //A 4x4 texture of a single colour.
+    fn flat(red: f32, green: f32, blue: f32) -> Texture {
+        let mut image = Framebuffer::new(4, 4);
+        for y in 0..4 {
+            for x in 0..4 {
+                image.put(x, y, Color { red, green, blue });
+            }
+        }
+        Texture::from_image(image)
+    }
+
+    fn assert_same(a: Vector3, b: Vector3) {
+        assert!((a - b).length() < 1e-9, "{:?} != {:?}", a, b);
+    }
+
+    #[test]
+    fn test_flat_maps_leave_the_normal_alone() {
+        let sphere = Sphere {
+            centre: Point {
+                x: 0.0,
+                y: 0.0,
+                z: -5.0,
+            },
+            radius: 1.0,
+            ..Default::default()
+        };
+        let normal = Vector3 {
+            x: 0.48,
+            y: 0.6,
+            z: 0.64,
+        };
+        let hit_point = sphere.centre + normal;
+
+        let mut bumped = material();
+        bumped.bump_map = Some(BumpMap {
+            texture: flat(0.7, 0.7, 0.7),
+            height: 0.5,
+        });
+        assert_same(shading_normal(&bumped, &sphere, &hit_point, normal), normal);
+
+        let mut mapped = material();
+        mapped.normal_map = Some(NormalMap {
+            texture: flat(0.5, 0.5, 1.0),
+            strength: 1.0,
+        });
+        assert_same(shading_normal(&mapped, &sphere, &hit_point, normal), normal);
+    }
+
+    #[test]
+    fn test_bump_map_tilts_the_normal_down_its_slope() {
+        //A floor, with its height climbing along texture x.
+        let plane = Plane {
+            origin: Point::zero(),
+            normal: Vector3 {
+                x: 0.0,
+                y: -1.0,
+                z: 0.0,
+            },
+            ..Default::default()
+        };
+        let mut image = Framebuffer::new(4, 1);
+        for x in 0..4 {
+            let value = x as f32 / 3.0;
+            image.put(x, 0, Color {
+                red: value,
+                green: value,
+                blue: value,
+            });
+        }
+        let mut bumped = material();
+        bumped.bump_map = Some(BumpMap {
+            texture: Texture::from_image(image),
+            height: 0.1,
+        });
+        let (dp_du, _) = plane.tangent_frame(&Point::zero());
+        let hit_point = Point::zero() + dp_du * 0.5;
+        let up = plane.surface_normal(&hit_point);
+        let shaded = shading_normal(&bumped, &plane, &hit_point, up);
+        assert!(shaded.dot(&dp_du) < 0.0);
+        assert!(shaded.dot(&up) > 0.0 && shaded.dot(&up) < 1.0);
+    }
+}
@@ -0,0 +1,436 @@
+use point::Point;
+use rendering::Ray;
+use scene::{Disk, Mesh, Sphere, Triangle};
+use vector::Vector3;
+
+const BINS: usize = 12;
+const MAX_LEAF_SIZE: usize = 4;
+const TRAVERSAL_COST: f64 = 1.0;
+
+#[derive(Clone, Copy, Debug)]
+pub struct Aabb {
+    pub min: Point,
+    pub max: Point,
+}
+
+impl Aabb {
+    pub fn empty() -> Aabb {
+        Aabb {
+            min: Point::from_one(f64::INFINITY),
+            max: Point::from_one(f64::NEG_INFINITY),
+        }
+    }
+
+    pub fn from_points(points: &[Point]) -> Aabb {
+        points.iter().fold(Aabb::empty(), |b, p| b.grow(p))
+    }
+
+    pub fn grow(&self, point: &Point) -> Aabb {
+        Aabb {
+            min: Point {
+                x: self.min.x.min(point.x),
+                y: self.min.y.min(point.y),
+                z: self.min.z.min(point.z),
+            },
+            max: Point {
+                x: self.max.x.max(point.x),
+                y: self.max.y.max(point.y),
+                z: self.max.z.max(point.z),
+            },
+        }
+    }
+
+    pub fn union(&self, other: &Aabb) -> Aabb {
+        Aabb {
+            min: Point {
+                x: self.min.x.min(other.min.x),
+                y: self.min.y.min(other.min.y),
+                z: self.min.z.min(other.min.z),
+            },
+            max: Point {
+                x: self.max.x.max(other.max.x),
+                y: self.max.y.max(other.max.y),
+                z: self.max.z.max(other.max.z),
+            },
+        }
+    }
+
+    pub fn centroid(&self) -> Point {
+        self.min + (self.max - self.min) * 0.5
+    }
+
+    pub fn surface_area(&self) -> f64 {
+        let d = self.max - self.min;
+        if d.x < 0.0 || d.y < 0.0 || d.z < 0.0 {
+            return 0.0;
+        }
+        2.0 * (d.x * d.y + d.y * d.z + d.z * d.x)
+    }
+
+    fn largest_axis(&self) -> usize {
+        let d = self.max - self.min;
+        if d.x >= d.y && d.x >= d.z {
+            0
+        } else if d.y >= d.z {
+            1
+        } else {
+            2
+        }
+    }
+
+    // Slab test, returning the distance at which the ray enters the box.
+    pub fn intersect(&self, ray: &Ray, inv_direction: &Vector3, max_distance: f64) -> Option<f64> {
+        let tx1 = (self.min.x - ray.origin.x) * inv_direction.x;
+        let tx2 = (self.max.x - ray.origin.x) * inv_direction.x;
+        let ty1 = (self.min.y - ray.origin.y) * inv_direction.y;
+        let ty2 = (self.max.y - ray.origin.y) * inv_direction.y;
+        let tz1 = (self.min.z - ray.origin.z) * inv_direction.z;
+        let tz2 = (self.max.z - ray.origin.z) * inv_direction.z;
+
+        let t_near = tx1.min(tx2).max(ty1.min(ty2)).max(tz1.min(tz2));
+        let t_far = tx1.max(tx2).min(ty1.max(ty2)).min(tz1.max(tz2));
+        if t_far < 0.0 || t_near > t_far || t_near > max_distance {
+            None
+        } else {
+            Some(t_near)
+        }
+    }
+}
+
+fn component(point: &Point, axis: usize) -> f64 {
+    match axis {
+        0 => point.x,
+        1 => point.y,
+        _ => point.z,
+    }
+}
+
+pub trait Bounded {
+    fn bounds(&self) -> Aabb;
+}
+
+impl Bounded for Sphere {
+    fn bounds(&self) -> Aabb {
+        Aabb {
+            min: self.centre - Vector3::from_one(self.radius),
+            max: self.centre + Vector3::from_one(self.radius),
+        }
+    }
+}
+
+impl Bounded for Disk {
+    fn bounds(&self) -> Aabb {
+        let n = self.normal;
+        let extent = Vector3 {
+            x: self.radius * (1.0 - n.x * n.x).max(0.0).sqrt(),
+            y: self.radius * (1.0 - n.y * n.y).max(0.0).sqrt(),
+            z: self.radius * (1.0 - n.z * n.z).max(0.0).sqrt(),
+        };
+        Aabb {
+            min: self.origin - extent,
+            max: self.origin + extent,
+        }
+    }
+}
+
+impl Bounded for Triangle {
+    fn bounds(&self) -> Aabb {
+        self.face.bounds()
+    }
+}
+
+impl Bounded for Mesh {
+    fn bounds(&self) -> Aabb {
+        self.model.bvh.bounds()
+    }
+}
+
+struct Node {
+    bounds: Aabb,
+    //Leaves hold `count` items starting at `first`; interior nodes have count == 0, their
+    //left child directly after them and their right child at `first`.
+    first: usize,
+    count: usize,
+}
+
+struct BuildItem {
+    index: usize,
+    bounds: Aabb,
+    centroid: Point,
+}
+
+#[derive(Default)]
+pub struct Bvh {
+    nodes: Vec<Node>,
+    indices: Vec<usize>,
+}
+
+impl Bvh {
+    pub fn new<I>(items: I) -> Bvh
+    where
+        I: IntoIterator<Item = (usize, Aabb)>,
+    {
+        let mut build_items: Vec<BuildItem> = items
+            .into_iter()
+            .map(|(index, bounds)| BuildItem {
+                index,
+                bounds,
+                centroid: bounds.centroid(),
+            })
+            .collect();
+        let mut bvh = Bvh {
+            nodes: Vec::with_capacity(2 * build_items.len()),
+            indices: Vec::with_capacity(build_items.len()),
+        };
+        if !build_items.is_empty() {
+            bvh.build(&mut build_items);
+        }
+        bvh
+    }
+
+    pub fn from_items<T: Bounded>(items: &[T]) -> Bvh {
+        Bvh::new(items.iter().map(|i| i.bounds()).enumerate())
+    }
+
+    pub fn bounds(&self) -> Aabb {
+        self.nodes.first().map(|n| n.bounds).unwrap_or(Aabb::empty())
+    }
+
+    fn build(&mut self, items: &mut [BuildItem]) {
+        let bounds = items.iter().fold(Aabb::empty(), |b, i| b.union(&i.bounds));
+        let node_index = self.nodes.len();
+        self.nodes.push(Node {
+            bounds,
+            first: self.indices.len(),
+            count: items.len(),
+        });
+
+        let split = if items.len() > 1 {
+            find_split(items, &bounds)
+        } else {
+            None
+        };
+        match split {
+            Some(mid) => {
+                let (left, right) = items.split_at_mut(mid);
+                self.build(left);
+                self.nodes[node_index].first = self.nodes.len();
+                self.nodes[node_index].count = 0;
+                self.build(right);
+            }
+            None => self.indices.extend(items.iter().map(|i| i.index)),
+        }
+    }
+
+    pub fn intersect<T, F>(&self, ray: &Ray, mut test: F) -> Option<(f64, T)>
+    where
+        F: FnMut(usize) -> Option<(f64, T)>,
+    {
+        let mut closest: Option<(f64, T)> = None;
+        self.traverse(ray, f64::INFINITY, |index, max_distance| {
+            if let Some(hit) = test(index) {
+                if hit.0 < *max_distance {
+                    *max_distance = hit.0;
+                    closest = Some(hit);
+                }
+            }
+            false
+        });
+        closest
+    }
+
+    pub fn any<F>(&self, ray: &Ray, max_distance: f64, mut test: F) -> bool
+    where
+        F: FnMut(usize) -> Option<f64>,
+    {
+        let mut found = false;
+        self.traverse(ray, max_distance, |index, _| {
+            found = test(index).map(|d| d < max_distance).unwrap_or(false);
+            found
+        });
+        found
+    }
+
+    //Visits every item whose node the ray reaches within max_distance. The visitor may
+    //shorten max_distance, and returns true to stop the traversal.
+    fn traverse<F>(&self, ray: &Ray, mut max_distance: f64, mut visit: F)
+    where
+        F: FnMut(usize, &mut f64) -> bool,
+    {
+        if self.nodes.is_empty() {
+            return;
+        }
+        let inv_direction = Vector3 {
+            x: 1.0 / ray.direction.x,
+            y: 1.0 / ray.direction.y,
+            z: 1.0 / ray.direction.z,
+        };
+        let mut stack: Vec<usize> = Vec::with_capacity(64);
+        stack.push(0);
+        while let Some(node_index) = stack.pop() {
+            let node = &self.nodes[node_index];
+            if node.bounds
+                .intersect(ray, &inv_direction, max_distance)
+                .is_none()
+            {
+                continue;
+            }
+            if node.count > 0 {
+                for &index in &self.indices[node.first..node.first + node.count] {
+                    if visit(index, &mut max_distance) {
+                        return;
+                    }
+                }
+            } else {
+                let left = node_index + 1;
+                let right = node.first;
+                let left_distance =
+                    self.nodes[left].bounds.intersect(ray, &inv_direction, max_distance);
+                let right_distance =
+                    self.nodes[right].bounds.intersect(ray, &inv_direction, max_distance);
+                match (left_distance, right_distance) {
+                    (Some(l), Some(r)) => {
+                        //Visit the nearer child first so hits there can prune the other.
+                        if l < r {
+                            stack.push(right);
+                            stack.push(left);
+                        } else {
+                            stack.push(left);
+                            stack.push(right);
+                        }
+                    }
+                    (Some(_), None) => stack.push(left),
+                    (None, Some(_)) => stack.push(right),
+                    (None, None) => {}
+                }
+            }
+        }
+    }
+}
+
+//Binned surface area heuristic. Returns the index to split the (reordered) items at, or None
+//if keeping them in a single leaf is cheaper.
+fn find_split(items: &mut [BuildItem], bounds: &Aabb) -> Option<usize> {
+    let centroid_bounds = items
+        .iter()
+        .fold(Aabb::empty(), |b, i| b.grow(&i.centroid));
+    let axis = centroid_bounds.largest_axis();
+    let min = component(&centroid_bounds.min, axis);
+    let extent = component(&centroid_bounds.max, axis) - min;
+    if extent <= 0.0 {
+        return if items.len() > MAX_LEAF_SIZE {
+            Some(items.len() / 2)
+        } else {
+            None
+        };
+    }
+
+    let bin_of = |item: &BuildItem| {
+        let offset = (component(&item.centroid, axis) - min) / extent;
+        ((offset * BINS as f64) as usize).min(BINS - 1)
+    };
+    let mut bin_counts = [0usize; BINS];
+    let mut bin_bounds = [Aabb::empty(); BINS];
+    for item in items.iter() {
+        let bin = bin_of(item);
+        bin_counts[bin] += 1;
+        bin_bounds[bin] = bin_bounds[bin].union(&item.bounds);
+    }
+
+    let mut best_cost = f64::INFINITY;
+    let mut best_bin = 0;
+    for split in 1..BINS {
+        let (mut left_bounds, mut left_count) = (Aabb::empty(), 0);
+        let (mut right_bounds, mut right_count) = (Aabb::empty(), 0);
+        for bin in 0..split {
+            left_bounds = left_bounds.union(&bin_bounds[bin]);
+            left_count += bin_counts[bin];
+        }
+        for bin in split..BINS {
+            right_bounds = right_bounds.union(&bin_bounds[bin]);
+            right_count += bin_counts[bin];
+        }
+        if left_count == 0 || right_count == 0 {
+            continue;
+        }
+        let cost = left_bounds.surface_area() * left_count as f64
+            + right_bounds.surface_area() * right_count as f64;
+        if cost < best_cost {
+            best_cost = cost;
+            best_bin = split;
+        }
+    }
+
+    let area = bounds.surface_area();
+    let split_cost = if area > 0.0 {
+        TRAVERSAL_COST + best_cost / area
+    } else {
+        TRAVERSAL_COST
+    };
+    if items.len() <= MAX_LEAF_SIZE && split_cost >= items.len() as f64 {
+        return None;
+    }
+
+    items.sort_by_key(|item| bin_of(item) >= best_bin);
+    let mid = items.iter().take_while(|item| bin_of(item) < best_bin).count();
+    Some(mid)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::rngs::StdRng;
+    use rand::{Rng, SeedableRng};
+
+    fn unit_box(centre: Point) -> Aabb {
+        Aabb {
+            min: centre - Vector3::from_one(0.5),
+            max: centre + Vector3::from_one(0.5),
+        }
+    }
+
+    #[test]
+    fn test_bvh_finds_same_hit_as_brute_force() {
+        let mut rng = StdRng::seed_from_u64(7);
+        let boxes: Vec<Aabb> = (0..200)
+            .map(|_| {
+                unit_box(Point {
+                    x: rng.gen_range(-20.0, 20.0),
+                    y: rng.gen_range(-20.0, 20.0),
+                    z: rng.gen_range(-40.0, -5.0),
+                })
+            })
+            .collect();
+        let bvh = Bvh::new(boxes.iter().cloned().enumerate());
+
+        for _ in 0..500 {
+            let ray = Ray {
+                origin: Point::zero(),
+                direction: Vector3 {
+                    x: rng.gen_range(-1.0, 1.0),
+                    y: rng.gen_range(-1.0, 1.0),
+                    z: -1.0,
+                }.normalise(),
+            };
+            let inv = Vector3 {
+                x: 1.0 / ray.direction.x,
+                y: 1.0 / ray.direction.y,
+                z: 1.0 / ray.direction.z,
+            };
+            let test = |i: usize| {
+                boxes[i]
+                    .intersect(&ray, &inv, f64::INFINITY)
+                    .map(|d| (d, i))
+            };
+            let expected = (0..boxes.len())
+                .filter_map(&test)
+                .min_by(|a, b| a.0.partial_cmp(&b.0).unwrap());
+            let found = bvh.intersect(&ray, &test);
+            assert_eq!(found.map(|h| h.1), expected.map(|h| h.1));
+            assert_eq!(
+                bvh.any(&ray, f64::INFINITY, |i| test(i).map(|h| h.0)),
+                expected.is_some()
+            );
+        }
+    }
+}
@@ -0,0 +1,182 @@
+use mesh::Face;
+use point::Point;
+use rand::Rng;
+use sampling::{concentric_disk, orthonormal_basis, sphere_cone, Distribution1D};
+use scene::{Color, Element, LightSample};
+use std::f64::consts::PI;
+use vector::Vector3;
+
+//Shadow rays towards an emitter stop this fraction short of it, so they miss the emitter itself.
+const SHADOW_MARGIN: f64 = 1e-4;
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Emission {
+    pub color: Color,
+    #[serde(default = "default_strength")]
+    pub strength: f32,
+    //Shadow rays per shading point when the element lights other surfaces.
+    #[serde(default = "default_emission_samples")]
+    pub samples: u32,
+}
+
+fn default_strength() -> f32 {
+    1.0
+}
+
+fn default_emission_samples() -> u32 {
+    16
+}
+
+impl Emission {
+    pub fn radiance(&self) -> Color {
+        self.color * self.strength
+    }
+}
+
+//An emissive element that lights the scene like an area light. Only bounded elements can be
+//sampled, so an emissive plane is seen when hit but lights nothing directly.
+pub struct Emitter {
+    pub element: usize,
+    //Mesh faces, chosen in proportion to their area.
+    faces: Option<Distribution1D>,
+}
+
+impl Emitter {
+    pub fn new(index: usize, element: &Element) -> Option<Emitter> {
+        if element.material().emission.is_none() || !is_sampled(element) {
+            return None;
+        }
+        Some(Emitter {
+            element: index,
+            faces: match *element {
+                Element::Mesh(ref m) => {
+                    let areas: Vec<f64> = m.model.faces.iter().map(face_area).collect();
+                    Some(Distribution1D::new(&areas))
+                }
+                _ => None,
+            },
+        })
+    }
+
+    pub fn sample<R: Rng>(
+        &self,
+        element: &Element,
+        hit_point: &Point,
+        rng: &mut R,
+    ) -> Option<LightSample> {
+        let emission = element.material().emission.as_ref()?;
+        //A point on the surface with its normal and the density of choosing it, per unit area,
+        //and whether the surface glows from both sides.
+        let (point, normal, density, two_sided) = match *element {
+            Element::Sphere(ref s) => {
+                let (direction, distance, solid_angle) =
+                    sphere_cone(&s.centre, s.radius, hit_point, rng)?;
+                return Some(LightSample {
+                    direction,
+                    distance: distance * (1.0 - SHADOW_MARGIN),
+                    color: emission.color,
+                    intensity: emission.strength * solid_angle as f32,
+                });
+            }
+            Element::Disk(ref d) => {
+                let (x, y) = concentric_disk(rng);
+                let (tangent, bitangent) = orthonormal_basis(&d.normal);
+                //Disks are only hit, and so only seen, from the side their surface normal faces.
+                (
+                    d.origin + (tangent * (x * d.radius)) + (bitangent * (y * d.radius)),
+                    -d.normal,
+                    1.0 / (PI * d.radius * d.radius),
+                    false,
+                )
+            }
+            Element::Triangle(ref t) => {
+                let (point, normal) = sample_face(&t.face, rng);
+                (point, normal, 1.0 / face_area(&t.face), true)
+            }
+            Element::Mesh(ref m) => {
+                let (index, probability) = self.faces.as_ref()?.sample(rng.gen());
+                let face = &m.model.faces[index];
+                let (point, normal) = sample_face(face, rng);
+                (point, normal, probability / face_area(face), true)
+            }
+            Element::Plane(_) => return None,
+        };
+        let to_light = point - *hit_point;
+        let distance = to_light.length();
+        let direction = to_light * (1.0 / distance);
+        let cos_light = if two_sided {
+            normal.dot(&direction).abs()
+        } else {
+            -normal.dot(&direction)
+        };
+        if cos_light <= 0.0 || density <= 0.0 || !density.is_finite() {
+            return None;
+        }
+        Some(LightSample {
+            direction,
+            distance: distance * (1.0 - SHADOW_MARGIN),
+            color: emission.color,
+            intensity: emission.strength * (cos_light / (distance * distance * density)) as f32,
+        })
+    }
+}
+
+//Whether light sampling already accounts for an element's emission.
+pub fn is_sampled(element: &Element) -> bool {
+    element.bounds().is_some()
+}
+
+fn face_area(face: &Face) -> f64 {
+    let edge1 = face.vertices[1] - face.vertices[0];
+    let edge2 = face.vertices[2] - face.vertices[0];
+    0.5 * edge1.cross(&edge2).length()
+}
+
+fn sample_face<R: Rng>(face: &Face, rng: &mut R) -> (Point, Vector3) {
+    let root = rng.gen::<f64>().sqrt();
+    let v = 1.0 - root;
+    let w = rng.gen::<f64>() * root;
+    let point = face.vertices[0]
+        + (face.vertices[1] - face.vertices[0]) * v
+        + (face.vertices[2] - face.vertices[0]) * w;
+    (point, face.geometric_normal())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::rngs::StdRng;
+    use scene::Scene;
+    use rand::SeedableRng;
+
+    #[test]
+    fn test_disk_emits_only_from_its_front() {
+        //A glowing disk facing down.
+        let scene = Scene::with(
+            r#"[{"Disk": {"origin": {"x": 0.0, "y": 0.0, "z": 0.0},
+                    "normal": {"x": 0.0, "y": 1.0, "z": 0.0}, "radius": 1.0,
+                    "material": {"coloration": {"Color": {"red": 0.0, "green": 0.0, "blue": 0.0}},
+                                 "albedo": 0.0, "surface": "Diffuse",
+                                 "emission": {"color": {"red": 1.0, "green": 1.0, "blue": 1.0}}}}}]"#,
+            "[]",
+        );
+        let disk = &scene.elements[0];
+        let emitter = &scene.emitters[0];
+        let below = Point {
+            x: 0.2,
+            y: -1.0,
+            z: 0.1,
+        };
+        let above = Point {
+            x: 0.2,
+            y: 1.0,
+            z: 0.1,
+        };
+        let mut rng = StdRng::seed_from_u64(0);
+        for _ in 0..100 {
+            let sample = emitter.sample(disk, &below, &mut rng).unwrap();
+            assert!(sample.direction.y > 0.0 && sample.intensity > 0.0);
+            assert!(emitter.sample(disk, &above, &mut rng).is_none());
+        }
+    }
+}
@@ -0,0 +1,351 @@
+use framebuffer::Framebuffer;
+use rand::Rng;
+use sampling::Distribution1D;
+use scene::{Camera, Color, Light, LightSample};
+use sky::Sky;
+use serde::de::Error;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use std::f64::consts::PI;
+use std::path::{Path, PathBuf};
+use vector::Vector3;
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub enum EnvironmentSource {
+    Equirectangular(PathBuf),
+    //Faces as seen from inside the cube, with front being the -z face the default camera
+    //looks at.
+    CubeMap {
+        right: PathBuf,
+        left: PathBuf,
+        top: PathBuf,
+        bottom: PathBuf,
+        front: PathBuf,
+        back: PathBuf,
+    },
+    Sky(Sky),
+}
+
+//The sky varies smoothly enough that a small image loses nothing.
+const SKY_WIDTH: u32 = 512;
+
+//Radiance from every direction, stored as an equirectangular image with u = 0 towards -z
+//and v = 0 straight up.
+pub struct EnvironmentMap {
+    pub source: EnvironmentSource,
+    pub image: Framebuffer,
+    rows: Distribution1D,
+    columns: Vec<Distribution1D>,
+}
+
+impl EnvironmentMap {
+    pub fn load(source: EnvironmentSource) -> Result<EnvironmentMap, String> {
+        let image = match source {
+            EnvironmentSource::Equirectangular(ref path) => load_image(path)?,
+            EnvironmentSource::CubeMap {
+                ref right,
+                ref left,
+                ref top,
+                ref bottom,
+                ref front,
+                ref back,
+            } => {
+                let faces = [
+                    load_image(right)?,
+                    load_image(left)?,
+                    load_image(top)?,
+                    load_image(bottom)?,
+                    load_image(front)?,
+                    load_image(back)?,
+                ];
+                cube_to_equirectangular(&faces)
+            }
+            EnvironmentSource::Sky(ref sky) => sky.render(SKY_WIDTH, SKY_WIDTH / 2),
+        };
+        Ok(EnvironmentMap::from_image(source, image))
+    }
+
+    pub fn from_image(source: EnvironmentSource, image: Framebuffer) -> EnvironmentMap {
+        //Rows near the poles cover less of the sphere, so they are weighted by sin(theta).
+        let columns: Vec<Distribution1D> = (0..image.height)
+            .map(|y| {
+                let sin_theta = (PI * (y as f64 + 0.5) / image.height as f64).sin();
+                let weights: Vec<f64> = (0..image.width)
+                    .map(|x| image.get(x, y).luminance() as f64 * sin_theta)
+                    .collect();
+                Distribution1D::new(&weights)
+            })
+            .collect();
+        let row_weights: Vec<f64> = columns.iter().map(|c| c.total()).collect();
+        EnvironmentMap {
+            source,
+            rows: Distribution1D::new(&row_weights),
+            columns,
+            image,
+        }
+    }
+
+    pub fn color(&self, direction: &Vector3) -> Color {
+        let (u, v) = direction_to_uv(direction);
+        self.image.get(
+            ((u * self.image.width as f64) as u32).min(self.image.width - 1),
+            ((v * self.image.height as f64) as u32).min(self.image.height - 1),
+        )
+    }
+
+    //Direction picked in proportion to the map's brightness, with its radiance and its
+    //probability density over solid angle.
+    pub fn sample<R: Rng>(&self, rng: &mut R) -> (Vector3, Color, f64) {
+        let (y, row_probability) = self.rows.sample(rng.gen());
+        let (x, column_probability) = self.columns[y].sample(rng.gen());
+        let u = (x as f64 + rng.gen::<f64>()) / self.image.width as f64;
+        let v = (y as f64 + rng.gen::<f64>()) / self.image.height as f64;
+        let direction = uv_to_direction(u, v);
+        let sin_theta = (PI * v).sin().max(1e-6);
+        let pdf = row_probability * column_probability
+            * (self.image.width * self.image.height) as f64
+            / (2.0 * PI * PI * sin_theta);
+        (direction, self.image.get(x as u32, y as u32), pdf)
+    }
+}
+
+fn load_image(path: &Path) -> Result<Framebuffer, String> {
+    Framebuffer::load(path).map_err(|e| format!("unable to open environment {}: {}", path.display(), e))
+}
+
+pub fn direction_to_uv(direction: &Vector3) -> (f64, f64) {
+    let phi = direction.x.atan2(-direction.z);
+    let u = phi / (2.0 * PI);
+    (
+        if u < 0.0 { u + 1.0 } else { u },
+        direction.y.clamp(-1.0, 1.0).acos() / PI,
+    )
+}
+
+pub fn uv_to_direction(u: f64, v: f64) -> Vector3 {
+    let phi = 2.0 * PI * u;
+    let theta = PI * v;
+    Vector3 {
+        x: theta.sin() * phi.sin(),
+        y: theta.cos(),
+        z: -theta.sin() * phi.cos(),
+    }
+}
+
+//Resamples six faces (right, left, top, bottom, front, back) into one equirectangular image.
+fn cube_to_equirectangular(faces: &[Framebuffer; 6]) -> Framebuffer {
+    let size = faces.iter().map(|f| f.width).max().unwrap();
+    let mut image = Framebuffer::new(4 * size, 2 * size);
+    for y in 0..image.height {
+        for x in 0..image.width {
+            let d = uv_to_direction(
+                (x as f64 + 0.5) / image.width as f64,
+                (y as f64 + 0.5) / image.height as f64,
+            );
+            let (ax, ay, az) = (d.x.abs(), d.y.abs(), d.z.abs());
+            //Face, then its horizontal and downward coordinates in [-1, 1].
+            let (face, s, t) = if ax >= ay && ax >= az {
+                if d.x > 0.0 {
+                    (0, d.z / ax, -d.y / ax)
+                } else {
+                    (1, -d.z / ax, -d.y / ax)
+                }
+            } else if ay >= az {
+                if d.y > 0.0 {
+                    (2, d.x / ay, d.z / ay)
+                } else {
+                    (3, d.x / ay, -d.z / ay)
+                }
+            } else if d.z < 0.0 {
+                (4, d.x / az, -d.y / az)
+            } else {
+                (5, -d.x / az, -d.y / az)
+            };
+            let face = &faces[face];
+            let fx = ((s + 1.0) * 0.5 * face.width as f64) as u32;
+            let fy = ((t + 1.0) * 0.5 * face.height as f64) as u32;
+            image.put(
+                x,
+                y,
+                face.get(fx.min(face.width - 1), fy.min(face.height - 1)),
+            );
+        }
+    }
+    image
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct Environment {
+    #[serde(rename = "source", deserialize_with = "load_environment",
+            serialize_with = "write_environment_source")]
+    pub map: EnvironmentMap,
+    //Degrees about the world y axis.
+    #[serde(default)]
+    pub rotation: f64,
+    #[serde(default = "default_intensity")]
+    pub intensity: f32,
+    #[serde(default = "default_environment_samples")]
+    pub samples: u32,
+    //The sun that comes with a physical sky, lighting the scene alongside its lights.
+    #[serde(skip)]
+    pub sun: Option<Light>,
+}
+
+fn default_intensity() -> f32 {
+    1.0
+}
+
+fn default_environment_samples() -> u32 {
+    16
+}
+
+impl Environment {
+    pub fn prepare(&mut self) {
+        self.sun = match self.map.source {
+            EnvironmentSource::Sky(ref sky) => sky.sun().map(|mut sun| {
+                sun.direction = self.rotate(&sun.direction, self.rotation);
+                Light::Directional(sun)
+            }),
+            _ => None,
+        };
+    }
+
+    pub fn color(&self, direction: &Vector3) -> Color {
+        self.map.color(&self.rotate(direction, -self.rotation)) * self.intensity
+    }
+
+    pub fn sample<R: Rng>(&self, rng: &mut R) -> Option<LightSample> {
+        let (direction, color, pdf) = self.map.sample(rng);
+        if pdf <= 0.0 {
+            return None;
+        }
+        Some(LightSample {
+            direction: self.rotate(&direction, self.rotation),
+            distance: f64::INFINITY,
+            color,
+            intensity: self.intensity / pdf as f32,
+        })
+    }
+
+    fn rotate(&self, direction: &Vector3, degrees: f64) -> Vector3 {
+        let (sin, cos) = degrees.to_radians().sin_cos();
+        Vector3 {
+            x: direction.x * cos + direction.z * sin,
+            y: direction.y,
+            z: -direction.x * sin + direction.z * cos,
+        }
+    }
+}
+
+fn load_environment<'de, D>(deserializer: D) -> Result<EnvironmentMap, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let source = EnvironmentSource::deserialize(deserializer)?;
+    EnvironmentMap::load(source).map_err(D::Error::custom)
+}
+
+fn write_environment_source<S>(map: &EnvironmentMap, serializer: S) -> Result<S::Ok, S::Error>
+where
+    S: Serializer,
+{
+    map.source.serialize(serializer)
+}
+
+//A backdrop for rays that miss everything, which unlike an environment lights nothing.
+#[derive(Serialize, Deserialize)]
+pub enum Background {
+    Color(Color),
+    //Blends by the ray's height: nadir straight down, horizon (if given) level, zenith
+    //straight up.
+    Gradient {
+        zenith: Color,
+        #[serde(default)]
+        horizon: Option<Color>,
+        nadir: Color,
+    },
+    //Fills the frame; reflections see whichever part of it lies in their direction.
+    Backplate(Backplate),
+}
+
+pub struct Backplate {
+    pub path: PathBuf,
+    pub image: Framebuffer,
+}
+
+impl Background {
+    //`sensor` is the camera's image plane half width and height, from `sensor_size`.
+    pub fn color(&self, direction: &Vector3, camera: &Camera, sensor: (f64, f64)) -> Color {
+        match *self {
+            Background::Color(c) => c,
+            Background::Gradient {
+                zenith,
+                horizon,
+                nadir,
+            } => {
+                let height = direction.y.clamp(-1.0, 1.0) as f32;
+                match horizon {
+                    Some(horizon) if height >= 0.0 => lerp(horizon, zenith, height),
+                    Some(horizon) => lerp(horizon, nadir, -height),
+                    None => lerp(nadir, zenith, 0.5 * (height + 1.0)),
+                }
+            }
+            Background::Backplate(ref backplate) => {
+                //Undoes Ray::create_prime; directions behind the camera end up at the edges.
+                let local = camera.rotation_matrix.transpose() * *direction;
+                let depth = (-local.z).max(1e-6);
+                let screen_x = local.x / depth / sensor.0;
+                let screen_y = local.y / depth / sensor.1;
+                let image = &backplate.image;
+                let x = (0.5 * (screen_x + 1.0) * image.width as f64).max(0.0) as u32;
+                let y = (0.5 * (1.0 - screen_y) * image.height as f64).max(0.0) as u32;
+                image.get(x.min(image.width - 1), y.min(image.height - 1))
+            }
+        }
+    }
+}
+
+fn lerp(from: Color, to: Color, t: f32) -> Color {
+    from * (1.0 - t) + to * t
+}
+
+impl Serialize for Backplate {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        self.path.serialize(serializer)
+    }
+}
+
+impl<'de> Deserialize<'de> for Backplate {
+    fn deserialize<D>(deserializer: D) -> Result<Backplate, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        let path = PathBuf::deserialize(deserializer)?;
+        let image = Framebuffer::load(&path).map_err(|e| {
+            D::Error::custom(format!("unable to open backplate {}: {}", path.display(), e))
+        })?;
+        Ok(Backplate {
+            path,
+            image,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_uv_round_trip() {
+        let direction = Vector3 {
+            x: 0.3,
+            y: -0.5,
+            z: 0.8,
+        }.normalise();
+        let (u, v) = direction_to_uv(&direction);
+        let back = uv_to_direction(u, v);
+        assert!((back - direction).length() < 1e-9);
+    }
+}
@@ -0,0 +1,246 @@
+use image::ImageError;
+use serde::de::{Deserialize, Deserializer, SeqAccess, Visitor};
+use serde_json;
+use serde_yaml;
+use std::cell::Cell;
+use std::error::Error;
+use std::fmt;
+use std::io;
+use std::marker::PhantomData;
+use std::path::PathBuf;
+
+#[derive(Debug)]
+pub enum SceneError {
+    Io {
+        path: PathBuf,
+        error: io::Error,
+    },
+    UnsupportedFormat {
+        path: PathBuf,
+    },
+    Parse {
+        path: PathBuf,
+        line: usize,
+        column: usize,
+        //The list and index of the item being read when the error happened, if any.
+        item: Option<(&'static str, usize)>,
+        message: String,
+    },
+    Texture {
+        path: PathBuf,
+        //The element or light whose texture it was.
+        item: (&'static str, usize),
+        texture: PathBuf,
+        //Boxed, as it's the bulk of the error otherwise.
+        error: Box<ImageError>,
+    },
+    //A table of texture settings whose path is the name of one of the scene's textures.
+    NamedTexture {
+        path: PathBuf,
+        item: (&'static str, usize),
+        name: String,
+    },
+}
+
+impl SceneError {
+    pub fn from_json(path: PathBuf, error: serde_json::Error) -> SceneError {
+        let (line, column) = (error.line(), error.column());
+        SceneError::parse(path, line, column, error.to_string())
+    }
+
+    pub fn from_yaml(path: PathBuf, error: serde_yaml::Error) -> SceneError {
+        let (line, column) = error
+            .location()
+            .map(|l| (l.line(), l.column()))
+            .unwrap_or((0, 0));
+        SceneError::parse(path, line, column, error.to_string())
+    }
+
+    fn parse(path: PathBuf, line: usize, column: usize, message: String) -> SceneError {
+        //Both serde_json and serde_yaml append the position, which we report separately.
+        let suffix = format!(" at line {} column {}", line, column);
+        let message = match message.find(&suffix) {
+            Some(i) => format!("{}{}", &message[..i], &message[i + suffix.len()..]),
+            None => message,
+        };
+        SceneError::Parse {
+            path,
+            line,
+            column,
+            item: CURRENT_ITEM.with(|c| c.replace(None)),
+            message,
+        }
+    }
+}
+
+impl fmt::Display for SceneError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            SceneError::Io {
+                ref path,
+                ref error,
+            } => write!(f, "{}: {}", path.display(), error),
+            SceneError::UnsupportedFormat { ref path } => write!(
+                f,
+                "{}: scene files must have a .json, .yml or .yaml extension",
+                path.display()
+            ),
+            SceneError::Parse {
+                ref path,
+                line,
+                column,
+                item,
+                ref message,
+            } => {
+                write!(f, "{}:{}:{}: ", path.display(), line, column)?;
+                if let Some((list, index)) = item {
+                    //serde_yaml already prefixes messages with the path to the bad value.
+                    let prefix = format!("{}[{}]", list, index);
+                    if !message.starts_with(&prefix) {
+                        write!(f, "in {}: ", prefix)?;
+                    }
+                }
+                write!(f, "{}", message)
+            }
+            SceneError::Texture {
+                ref path,
+                item: (list, index),
+                ref texture,
+                ref error,
+            } => write!(
+                f,
+                "{}: in {}[{}]: unable to open texture {}: {}",
+                path.display(),
+                list,
+                index,
+                texture.display(),
+                error
+            ),
+            SceneError::NamedTexture {
+                ref path,
+                item: (list, index),
+                ref name,
+            } => write!(
+                f,
+                "{}: in {}[{}]: texture {} is one of the scene's textures, so takes its settings \
+                 from there and must be given as just the name",
+                path.display(),
+                list,
+                index,
+                name
+            ),
+        }
+    }
+}
+
+impl Error for SceneError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match *self {
+            SceneError::Io { ref error, .. } => Some(error),
+            SceneError::Texture { ref error, .. } => Some(&**error),
+            _ => None,
+        }
+    }
+}
+
+thread_local! {
+    static CURRENT_ITEM: Cell<Option<(&'static str, usize)>> = const { Cell::new(None) };
+}
+
+pub fn reset_current_item() {
+    CURRENT_ITEM.with(|c| c.set(None));
+}
+
+struct IndexedSeqVisitor<T> {
+    name: &'static str,
+    marker: PhantomData<T>,
+}
+
+impl<'de, T: Deserialize<'de>> Visitor<'de> for IndexedSeqVisitor<T> {
+    type Value = Vec<T>;
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "a list of {}", self.name)
+    }
+
+    fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<Vec<T>, A::Error> {
+        let mut items = Vec::new();
+        loop {
+            CURRENT_ITEM.with(|c| c.set(Some((self.name, items.len()))));
+            match seq.next_element()? {
+                Some(item) => items.push(item),
+                None => break,
+            }
+        }
+        CURRENT_ITEM.with(|c| c.set(None));
+        Ok(items)
+    }
+}
+
+//Records which item of a list is being read, so that errors can say which element was wrong
+//without losing the position serde gives them.
+fn deserialize_indexed<'de, D, T>(deserializer: D, name: &'static str) -> Result<Vec<T>, D::Error>
+where
+    D: Deserializer<'de>,
+    T: Deserialize<'de>,
+{
+    deserializer.deserialize_seq(IndexedSeqVisitor {
+        name,
+        marker: PhantomData,
+    })
+}
+
+pub fn deserialize_elements<'de, D, T>(deserializer: D) -> Result<Vec<T>, D::Error>
+where
+    D: Deserializer<'de>,
+    T: Deserialize<'de>,
+{
+    deserialize_indexed(deserializer, "elements")
+}
+
+pub fn deserialize_lights<'de, D, T>(deserializer: D) -> Result<Vec<T>, D::Error>
+where
+    D: Deserializer<'de>,
+    T: Deserialize<'de>,
+{
+    deserialize_indexed(deserializer, "lights")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::Path;
+
+    #[derive(Deserialize, Debug)]
+    struct Lights {
+        #[serde(deserialize_with = "deserialize_lights")]
+        lights: Vec<u32>,
+    }
+
+    #[test]
+    fn test_parse_error_reports_position_and_item() {
+        reset_current_item();
+        let lights = serde_json::from_str::<Lights>("{\"lights\": [1, 2]}").unwrap();
+        assert_eq!(lights.lights, vec![1, 2]);
+
+        let json = "{\n  \"lights\": [1, 2,\n    \"three\"]\n}";
+        let error = serde_json::from_str::<Lights>(json).unwrap_err();
+        let error = SceneError::from_json(Path::new("scene.json").to_path_buf(), error);
+        match error {
+            SceneError::Parse {
+                line,
+                column,
+                item,
+                ..
+            } => {
+                assert_eq!((line, column), (3, 11));
+                assert_eq!(item, Some(("lights", 2)));
+            }
+            _ => panic!("expected a parse error"),
+        }
+        assert_eq!(
+            error.to_string(),
+            "scene.json:3:11: in lights[2]: invalid type: string \"three\", expected u32"
+        );
+    }
+}
@@ -0,0 +1,171 @@
+use image;
+use image::{ColorType, DynamicImage, ImageBuffer, ImageFormat, ImageResult, Rgb, RgbImage};
+use scene::Color;
+use std::ffi::OsStr;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::Path;
+use tonemap::{srgb_decode, ToneMapper};
+
+pub struct Framebuffer {
+    pub width: u32,
+    pub height: u32,
+    pub pixels: Vec<Color>,
+}
+
+impl Framebuffer {
+    pub fn new(width: u32, height: u32) -> Framebuffer {
+        Framebuffer {
+            width,
+            height,
+            pixels: vec![Color::black(); (width * height) as usize],
+        }
+    }
+
+    pub fn get(&self, x: u32, y: u32) -> Color {
+        self.pixels[(y * self.width + x) as usize]
+    }
+
+    pub fn put(&mut self, x: u32, y: u32, color: Color) {
+        self.pixels[(y * self.width + x) as usize] = color;
+    }
+
+    // Floating point images are taken as linear radiance, anything else as sRGB.
+    pub fn load(path: &Path) -> ImageResult<Framebuffer> {
+        Framebuffer::load_with(path, true)
+    }
+
+    // For images holding values rather than colours, such as normal maps, which are never decoded.
+    pub fn load_data(path: &Path) -> ImageResult<Framebuffer> {
+        Framebuffer::load_with(path, false)
+    }
+
+    fn load_with(path: &Path, srgb: bool) -> ImageResult<Framebuffer> {
+        let image = image::open(path)?;
+        let linear = !srgb
+            || image.color() == ColorType::Rgb32F
+            || image.color() == ColorType::Rgba32F;
+        let buffer = image.to_rgb32f();
+        let pixels = buffer
+            .pixels()
+            .map(|p| {
+                let color = Color {
+                    red: p[0],
+                    green: p[1],
+                    blue: p[2],
+                };
+                if linear {
+                    color
+                } else {
+                    Color {
+                        red: srgb_decode(color.red),
+                        green: srgb_decode(color.green),
+                        blue: srgb_decode(color.blue),
+                    }
+                }
+            })
+            .collect();
+        Ok(Framebuffer {
+            width: buffer.width(),
+            height: buffer.height(),
+            pixels,
+        })
+    }
+
+    pub fn to_image(&self, tone_mapper: ToneMapper, exposure: f32) -> DynamicImage {
+        let scale = 2.0f32.powf(exposure);
+        let white = self.pixels
+            .iter()
+            .map(|c| c.luminance() * scale)
+            .fold(0.0, f32::max);
+        let image = RgbImage::from_fn(self.width, self.height, |x, y| {
+            let rgba = tone_mapper.map(self.get(x, y) * scale, white).to_rgba();
+            Rgb([rgba[0], rgba[1], rgba[2]])
+        });
+        DynamicImage::ImageRgb8(image)
+    }
+
+    pub fn to_hdr_image(&self) -> DynamicImage {
+        let image = ImageBuffer::from_fn(self.width, self.height, |x, y| {
+            let color = self.get(x, y);
+            Rgb([color.red, color.green, color.blue])
+        });
+        DynamicImage::ImageRgb32F(image)
+    }
+
+    pub fn can_save(path: &Path) -> bool {
+        extension(path).as_deref() == Some("pfm")
+            || ImageFormat::from_path(path)
+                .map(|f| f.can_write())
+                .unwrap_or(false)
+    }
+
+    // Floating point formats get the linear radiance, anything else is tone mapped to 8 bits.
+    pub fn save(&self, path: &Path, tone_mapper: ToneMapper, exposure: f32) -> ImageResult<()> {
+        match extension(path).as_deref() {
+            Some("exr") | Some("hdr") => self.to_hdr_image().save(path),
+            Some("pfm") => {
+                let mut writer = BufWriter::new(File::create(path)?);
+                self.write_pfm(&mut writer)?;
+                Ok(writer.flush()?)
+            }
+            _ => self.to_image(tone_mapper, exposure).save(path),
+        }
+    }
+
+    // Portable float map: a text header, then little endian RGB rows from the bottom up.
+    pub fn write_pfm<W: Write>(&self, writer: &mut W) -> ::std::io::Result<()> {
+        write!(writer, "PF\n{} {}\n-1.0\n", self.width, self.height)?;
+        for y in (0..self.height).rev() {
+            for x in 0..self.width {
+                let color = self.get(x, y);
+                for channel in &[color.red, color.green, color.blue] {
+                    writer.write_all(&channel.to_bits().to_le_bytes())?;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+//The file's extension in lower case, so `out.EXR` is written the same way as `out.exr`.
+fn extension(path: &Path) -> Option<String> {
+    path.extension()
+        .and_then(OsStr::to_str)
+        .map(str::to_ascii_lowercase)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_pfm_is_bottom_up_little_endian() {
+        let mut framebuffer = Framebuffer::new(1, 2);
+        framebuffer.put(
+            0,
+            1,
+            Color {
+                red: 2.5,
+                green: 0.0,
+                blue: 0.0,
+            },
+        );
+        let mut bytes = Vec::new();
+        framebuffer.write_pfm(&mut bytes).unwrap();
+
+        let header = b"PF\n1 2\n-1.0\n";
+        assert_eq!(&bytes[..header.len()], &header[..]);
+        assert_eq!(bytes.len(), header.len() + 2 * 3 * 4);
+        assert_eq!(&bytes[header.len()..header.len() + 4], &2.5f32.to_le_bytes());
+    }
+
+    #[test]
+    fn test_extensions_ignore_case() {
+        assert!(Framebuffer::can_save(Path::new("out.PFM")));
+        assert!(Framebuffer::can_save(Path::new("out.EXR")));
+        assert!(Framebuffer::can_save(Path::new("out.Png")));
+        assert!(!Framebuffer::can_save(Path::new("out.txt")));
+        assert_eq!(extension(Path::new("out.HDR")), Some(String::from("hdr")));
+    }
+}
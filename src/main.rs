@@ -8,25 +8,60 @@ extern crate serde;
 extern crate serde_json;
 extern crate serde_yaml;
 
+mod bump;
+mod bvh;
+mod emission;
+mod environment;
+mod error;
+mod framebuffer;
 mod matrix;
+mod medium;
+mod microfacet;
+mod mesh;
+mod path_tracing;
 mod point;
+mod procedural;
 mod rendering;
+mod sampling;
 mod scene;
+mod sky;
+mod spectrum;
+mod texture;
+mod tonemap;
 mod vector;
 
-use image::{DynamicImage, GenericImage};
+use bvh::Bvh;
+use framebuffer::Framebuffer;
 use point::Point;
+use path_tracing::trace_path;
+use medium::Media;
 use rendering::{cast_ray, Ray};
+use spectrum::{sample_wavelength, wavelength_weight};
 use scene::{
-    Camera, Color, Coloration, Element, Light, Material, Plane, Scene, Sphere,
+    Camera, Color, Coloration, Element, Integrator, Light, Material, Plane, Scene, Sphere,
     SphericalLight, SurfaceType,
 };
-use std::fs::File;
+use std::collections::BTreeMap;
+use std::process;
+use texture::TextureCache;
+use tonemap::{ToneMapper, TONE_MAPPERS};
 use vector::Vector3;
 use std::path::Path;
-use std::ffi::OsStr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc;
+use std::thread;
 use clap::{Arg, App, SubCommand};
-use rand::Rng;
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+
+const TILE_SIZE: u32 = 32;
+
+struct Tile {
+    x: u32,
+    y: u32,
+    width: u32,
+    height: u32,
+}
 
 
 fn main() {
@@ -40,6 +75,69 @@ fn main() {
             .value_name("FILE")
             .help("Sets an input scene file")
             .takes_value(true))
+        .arg(Arg::with_name("output")
+            .short("o")
+            .long("output")
+            .value_name("FILE")
+            .help("Sets the output image; the extension picks the format (defaults to output.png)")
+            .validator(output_format)
+            .takes_value(true))
+        .arg(Arg::with_name("width")
+            .long("width")
+            .value_name("PIXELS")
+            .help("Overrides the scene's image width")
+            .validator(positive)
+            .takes_value(true))
+        .arg(Arg::with_name("height")
+            .long("height")
+            .value_name("PIXELS")
+            .help("Overrides the scene's image height")
+            .validator(positive)
+            .takes_value(true))
+        .arg(Arg::with_name("samples")
+            .short("s")
+            .long("samples")
+            .value_name("N")
+            .help("Overrides the scene's samples per pixel")
+            .validator(positive)
+            .takes_value(true))
+        .arg(Arg::with_name("max_depth")
+            .long("max-depth")
+            .value_name("N")
+            .help("Overrides the scene's maximum recursion depth")
+            .validator(whole_number)
+            .takes_value(true))
+        .arg(Arg::with_name("fov")
+            .long("fov")
+            .value_name("DEGREES")
+            .help("Overrides the scene's field of view, across the shorter side of the image")
+            .validator(field_of_view)
+            .takes_value(true))
+        .arg(Arg::with_name("threads")
+            .short("t")
+            .long("threads")
+            .value_name("N")
+            .help("Sets the number of render threads (defaults to one per core)")
+            .validator(positive)
+            .takes_value(true))
+        .arg(Arg::with_name("exposure")
+            .long("exposure")
+            .value_name("EV")
+            .help("Overrides the scene's exposure, in stops")
+            .allow_hyphen_values(true)
+            .takes_value(true))
+        .arg(Arg::with_name("tonemap")
+            .long("tonemap")
+            .value_name("OPERATOR")
+            .help("Overrides the scene's tone mapper")
+            .possible_values(TONE_MAPPERS)
+            .takes_value(true))
+        .arg(Arg::with_name("dump_scene")
+            .long("dump-scene")
+            .value_name("FILE")
+            .help("Writes the scene, after any overrides, to a .json or .yml file instead of rendering")
+            .validator(scene_format)
+            .takes_value(true))
         .subcommand(SubCommand::with_name("random")
             .about("Specify a grid to populate with random shapes")          
             .arg(Arg::with_name("x")
@@ -51,17 +149,11 @@ fn main() {
                 .index(2)
                 .required(true)))
         .get_matches();
-    let mut scene: Scene = if matches.is_present("input_file") {
-        let filename = matches.value_of("input_file").unwrap();
-        let scene_file = File::open(filename).expect("File not found");
-        let extension = Path::new(filename).extension().and_then(OsStr::to_str);
-        if extension == Some("json") {
-            serde_json::from_reader(scene_file).unwrap()
-        } else if extension == Some("yml") {
-            serde_yaml::from_reader(scene_file).unwrap()
-        } else {
-            panic!("An input file path was provided but it wasn't a json or yml file.")
-        }
+    let mut scene: Scene = if let Some(filename) = matches.value_of("input_file") {
+        Scene::load(Path::new(filename)).unwrap_or_else(|e| {
+            eprintln!("error: {}", e);
+            process::exit(1)
+        })
     } else if let Some(matches) = matches.subcommand_matches("random") {
         let x = value_t!(matches, "x", i32).unwrap_or(3);
         let y = value_t!(matches, "y", i32).unwrap_or(3);
@@ -69,34 +161,158 @@ fn main() {
     } else {
         random_shapes(3, 3)
     };
-    scene.camera.rotation_matrix = Camera::calculate_rotation_matrix(
-        scene.camera.look_at,
-        scene.camera.position,
-        scene.camera.up,
-    );
-    let img: DynamicImage = render(&scene);
-    img.save("output.png").expect("Failed to save output image");
+    if matches.is_present("width") {
+        scene.width = value_t_or_exit!(matches, "width", u32);
+    }
+    if matches.is_present("height") {
+        scene.height = value_t_or_exit!(matches, "height", u32);
+    }
+    if matches.is_present("samples") {
+        scene.n_samples = value_t_or_exit!(matches, "samples", u32);
+    }
+    if matches.is_present("max_depth") {
+        scene.max_recursion_depth = value_t_or_exit!(matches, "max_depth", u32);
+    }
+    if matches.is_present("fov") {
+        scene.fov = value_t_or_exit!(matches, "fov", f64);
+    }
+    if matches.is_present("exposure") {
+        scene.exposure = value_t_or_exit!(matches, "exposure", f32);
+    }
+    if matches.is_present("tonemap") {
+        scene.tone_mapper = value_t_or_exit!(matches, "tonemap", ToneMapper);
+    }
+    if let Some(filename) = matches.value_of("dump_scene") {
+        if let Err(e) = scene.save(Path::new(filename)) {
+            eprintln!("error: {}", e);
+            process::exit(1);
+        }
+        return;
+    }
+    let threads = if matches.is_present("threads") {
+        value_t_or_exit!(matches, "threads", usize)
+    } else {
+        thread::available_parallelism()
+            .map(|n| n.get())
+            .unwrap_or(1)
+    };
+    let output = Path::new(matches.value_of("output").unwrap_or("output.png"));
+    let framebuffer = render(&scene, threads);
+    if let Err(e) = framebuffer.save(output, scene.tone_mapper, scene.exposure) {
+        eprintln!("error: unable to save {}: {}", output.display(), e);
+        process::exit(1);
+    }
 }
 
-fn render(scene: &Scene) -> DynamicImage {
-    let mut image = DynamicImage::new_rgb8(scene.width, scene.height);
+fn output_format(value: String) -> Result<(), String> {
+    if Framebuffer::can_save(Path::new(&value)) {
+        Ok(())
+    } else {
+        Err(String::from("unrecognised image format"))
+    }
+}
 
-    for x in 0..scene.width {
-        for y in 0..scene.height {
+fn scene_format(value: String) -> Result<(), String> {
+    match Path::new(&value).extension().and_then(|e| e.to_str()) {
+        Some("json") | Some("yml") | Some("yaml") => Ok(()),
+        _ => Err(String::from("scene files must have a .json, .yml or .yaml extension")),
+    }
+}
+
+fn positive(value: String) -> Result<(), String> {
+    match value.parse::<u32>() {
+        Ok(n) if n > 0 => Ok(()),
+        _ => Err(String::from("must be a whole number greater than zero")),
+    }
+}
+
+fn whole_number(value: String) -> Result<(), String> {
+    match value.parse::<u32>() {
+        Ok(_) => Ok(()),
+        _ => Err(String::from("must be a whole number")),
+    }
+}
+
+fn field_of_view(value: String) -> Result<(), String> {
+    match value.parse::<f64>() {
+        Ok(fov) if fov > 0.0 && fov < 180.0 => Ok(()),
+        _ => Err(String::from("must be an angle between 0 and 180 degrees")),
+    }
+}
+
+fn render(scene: &Scene, threads: usize) -> Framebuffer {
+    let tiles_across = scene.width.div_ceil(TILE_SIZE);
+    let tiles_down = scene.height.div_ceil(TILE_SIZE);
+    let n_tiles = (tiles_across * tiles_down) as usize;
+    let next_tile = AtomicUsize::new(0);
+    let (sender, receiver) = mpsc::channel();
+
+    thread::scope(|s| {
+        for _ in 0..threads {
+            let sender = sender.clone();
+            let next_tile = &next_tile;
+            s.spawn(move || loop {
+                let index = next_tile.fetch_add(1, Ordering::Relaxed);
+                if index >= n_tiles {
+                    break;
+                }
+                let x = (index as u32 % tiles_across) * TILE_SIZE;
+                let y = (index as u32 / tiles_across) * TILE_SIZE;
+                let tile = Tile {
+                    x,
+                    y,
+                    width: TILE_SIZE.min(scene.width - x),
+                    height: TILE_SIZE.min(scene.height - y),
+                };
+                // Seeding per tile rather than per thread keeps the output independent of
+                // which thread renders which tile.
+                let mut rng = StdRng::seed_from_u64(index as u64);
+                let pixels = render_tile(scene, &tile, &mut rng);
+                sender.send((tile, pixels)).unwrap();
+            });
+        }
+    });
+    drop(sender);
+
+    let mut framebuffer = Framebuffer::new(scene.width, scene.height);
+    for (tile, pixels) in receiver {
+        for (i, color) in pixels.into_iter().enumerate() {
+            let x = tile.x + i as u32 % tile.width;
+            let y = tile.y + i as u32 / tile.width;
+            framebuffer.put(x, y, color);
+        }
+    }
+    framebuffer
+}
+
+fn render_tile<R: Rng>(scene: &Scene, tile: &Tile, rng: &mut R) -> Vec<Color> {
+    let mut pixels = Vec::with_capacity((tile.width * tile.height) as usize);
+    for y in tile.y..tile.y + tile.height {
+        for x in tile.x..tile.x + tile.width {
             let mut color = Color::black();
-            for _ in 0..scene.n_samples {
+            for i in 0..scene.n_samples {
                 let ray = Ray::create_prime(
-                    (x as f32) + (rand::random::<f32>() - 0.5),
-                    (y as f32) + (rand::random::<f32>() - 0.5),
+                    (x as f32) + (rng.gen::<f32>() - 0.5),
+                    (y as f32) + (rng.gen::<f32>() - 0.5),
                     scene,
+                    rng,
                 );
-                color = color + cast_ray(scene, &ray, 0);
+                let (media, weight) = if scene.spectral {
+                    let wavelength = sample_wavelength(i, scene.n_samples, rng);
+                    (Media::with_wavelength(wavelength), wavelength_weight(wavelength))
+                } else {
+                    (Media::new(), Color::white())
+                };
+                let sample = match scene.integrator {
+                    Integrator::Whitted => cast_ray(scene, &ray, 0, &media, rng),
+                    Integrator::Path => trace_path(scene, &ray, media, rng),
+                };
+                color = color + sample * weight;
             }
-            color = color * (1.0 / scene.n_samples as f32);
-            image.put_pixel(x, y, color.to_rgba());
+            pixels.push(color * (1.0 / scene.n_samples as f32));
         }
     }
-    image
+    pixels
 }
 
 fn random_shapes(rows: i32, cols: i32) -> Scene {
@@ -155,6 +371,8 @@ fn random_shapes(rows: i32, cols: i32) -> Scene {
         position: position,
         look_at: look_at,
         up: up,
+        aperture: 0.0,
+        focus_distance: None,
         rotation_matrix: Camera::calculate_rotation_matrix(look_at, position, up),
     };
 
@@ -179,7 +397,7 @@ fn random_shapes(rows: i32, cols: i32) -> Scene {
         }),
     ];
 
-    Scene {
+    let mut scene = Scene {
         width: 800,
         height: 400,
         elements: elements,
@@ -189,5 +407,73 @@ fn random_shapes(rows: i32, cols: i32) -> Scene {
         shadow_bias: 1e-10,
         max_recursion_depth: 6,
         n_samples: 90,
+        integrator: Integrator::Whitted,
+        exposure: 0.0,
+        tone_mapper: ToneMapper::Clamp,
+        environment: None,
+        background: None,
+        hide_background_in_reflections: false,
+        spectral: false,
+        textures: BTreeMap::new(),
+        texture_cache: TextureCache::default(),
+        bvh: Bvh::default(),
+        unbounded: Vec::new(),
+        emitters: Vec::new(),
+    };
+    scene.prepare();
+    scene
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pixels(framebuffer: &Framebuffer) -> Vec<(f32, f32, f32)> {
+        framebuffer
+            .pixels
+            .iter()
+            .map(|c| (c.red, c.green, c.blue))
+            .collect()
+    }
+
+    #[test]
+    fn test_render_is_independent_of_thread_count() {
+        //Sizes that aren't a multiple of the tile size, so the edge tiles are partial.
+        let mut scene = random_shapes(3, 3);
+        scene.width = 70;
+        scene.height = 40;
+        scene.n_samples = 2;
+        let single = render(&scene, 1);
+        let threaded = render(&scene, 4);
+        assert_eq!(pixels(&single), pixels(&threaded));
+    }
+
+    #[test]
+    fn test_render_portrait_and_square_frames() {
+        let mut scene = random_shapes(3, 3);
+        scene.n_samples = 1;
+        for &(width, height) in &[(24, 40), (40, 40)] {
+            scene.width = width;
+            scene.height = height;
+            let framebuffer = render(&scene, 2);
+            assert_eq!(framebuffer.pixels.len(), (width * height) as usize);
+            assert!(pixels(&framebuffer)
+                .iter()
+                .all(|&(r, g, b)| r.is_finite() && g.is_finite() && b.is_finite()));
+        }
+    }
+
+    #[test]
+    fn test_field_of_view_spans_the_shorter_side() {
+        let mut scene = random_shapes(1, 1);
+        scene.fov = 90.0;
+        scene.width = 40;
+        scene.height = 80;
+        let mut rng = StdRng::seed_from_u64(0);
+        //The left edge of a portrait frame is 45 degrees off axis, the top edge further.
+        let left = Ray::create_prime(-0.5, 39.5, &scene, &mut rng).direction;
+        assert!((left.x / left.z - 1.0).abs() < 1e-9);
+        let top = Ray::create_prime(19.5, -0.5, &scene, &mut rng).direction;
+        assert!((top.y / -top.z - 2.0).abs() < 1e-9);
     }
 }
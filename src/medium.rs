@@ -0,0 +1,193 @@
+use scene::{Color, Element, SurfaceType};
+use std::ptr;
+
+//The dielectrics a ray is inside, innermost last. Where they overlap, the one with the highest
+//priority fills the space, so a liquid can be modelled slightly overlapping its glass and an
+//air bubble given a higher priority than the water around it.
+#[derive(Clone, Default)]
+pub struct Media<'a> {
+    stack: Vec<&'a Element>,
+    //The wavelength in nanometres a spectral ray carries, at which dispersive indices are taken.
+    wavelength: Option<f64>,
+}
+
+fn refractive(element: &Element) -> Option<(f32, Color, u32)> {
+    match element.material().surface {
+        SurfaceType::Refractive {
+            index,
+            absorption,
+            priority,
+            ..
+        } => Some((index, absorption, priority)),
+        _ => None,
+    }
+}
+
+impl<'a> Media<'a> {
+    pub fn new() -> Media<'a> {
+        Media {
+            stack: Vec::new(),
+            wavelength: None,
+        }
+    }
+
+    pub fn with_wavelength(wavelength: f64) -> Media<'a> {
+        Media {
+            stack: Vec::new(),
+            wavelength: Some(wavelength),
+        }
+    }
+
+    //The element's index of refraction for the wavelength being traced, if it is a dielectric.
+    fn index(&self, element: &Element) -> Option<f32> {
+        match element.material().surface {
+            SurfaceType::Refractive {
+                index,
+                ref dispersion,
+                ..
+            } => match (dispersion.as_ref(), self.wavelength) {
+                (Some(dispersion), Some(wavelength)) => Some(dispersion.index(wavelength)),
+                _ => Some(index),
+            },
+            _ => None,
+        }
+    }
+
+    //The medium filling the space the ray is in; later entries win ties.
+    fn current(&self, excluding: Option<&Element>) -> Option<&'a Element> {
+        let mut best: Option<(&'a Element, u32)> = None;
+        for &element in &self.stack {
+            if excluding.is_some_and(|x| ptr::eq(element, x)) {
+                continue;
+            }
+            if let Some((_, _, priority)) = refractive(element) {
+                if best.is_none_or(|(_, p)| priority >= p) {
+                    best = Some((element, priority));
+                }
+            }
+        }
+        best.map(|(element, _)| element)
+    }
+
+    fn contains(&self, element: &Element) -> bool {
+        self.stack.iter().any(|e| ptr::eq(*e, element))
+    }
+
+    //False for the surface of a dielectric inside one of higher priority, which the ray passes
+    //straight through.
+    pub fn is_interface(&self, element: &Element) -> bool {
+        let priority = match refractive(element) {
+            Some((_, _, priority)) => priority,
+            None => return true,
+        };
+        self.current(Some(element))
+            .and_then(refractive)
+            .is_none_or(|(_, _, other)| other <= priority)
+    }
+
+    //Index of refraction of the element relative to whatever is on the other side of its
+    //surface here, as `fresnel` and `Ray::create_transmission` expect.
+    pub fn relative_index(&self, element: &Element) -> f32 {
+        let outside = self.current(Some(element))
+            .and_then(|e| self.index(e))
+            .unwrap_or(1.0);
+        self.index(element).unwrap_or(1.0) / outside
+    }
+
+    pub fn absorption(&self) -> Color {
+        self.current(None)
+            .and_then(refractive)
+            .map_or(Color::black(), |(_, absorption, _)| absorption)
+    }
+
+    //The media on the far side of the element's surface.
+    pub fn crossed(&self, element: &'a Element) -> Media<'a> {
+        let mut media = self.clone();
+        if self.contains(element) {
+            let last = media.stack.iter().rposition(|e| ptr::eq(*e, element)).unwrap();
+            media.stack.remove(last);
+        } else if refractive(element).is_some() {
+            media.stack.push(element);
+        }
+        media
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use point::Point;
+    use scene::{Coloration, Material, Sphere};
+    use spectrum::Dispersion;
+
+    fn dielectric(index: f32, priority: u32) -> Element {
+        Element::Sphere(Sphere {
+            centre: Point::zero(),
+            radius: 1.0,
+            material: Material {
+                coloration: Coloration::Color(Color::white()),
+                albedo: 1.0,
+                surface: SurfaceType::Refractive {
+                    index,
+                    transparency: 1.0,
+                    absorption: Color::black(),
+                    roughness: 0.0,
+                    priority,
+                    dispersion: None,
+                },
+                specular: Color::black(),
+                shininess: 0.0,
+                emission: None,
+                normal_map: None,
+                bump_map: None,
+            },
+        })
+    }
+
+    #[test]
+    fn test_bubble_in_water_uses_relative_index() {
+        let water = dielectric(1.33, 1);
+        let bubble = dielectric(1.0, 2);
+        let air = Media::new();
+        assert_eq!(air.relative_index(&water), 1.33);
+        let in_water = air.crossed(&water);
+        assert!((in_water.relative_index(&bubble) - 1.0 / 1.33).abs() < 1e-6);
+        let in_bubble = in_water.crossed(&bubble);
+        assert!((in_bubble.relative_index(&bubble) - 1.0 / 1.33).abs() < 1e-6);
+        assert!(in_bubble.crossed(&bubble).crossed(&water).stack.is_empty());
+    }
+
+    #[test]
+    fn test_lower_priority_surface_inside_is_skipped() {
+        let glass = dielectric(1.5, 2);
+        let liquid = dielectric(1.33, 1);
+        let in_glass = Media::new().crossed(&glass);
+        assert!(!in_glass.is_interface(&liquid));
+        let in_both = in_glass.crossed(&liquid);
+        assert!(in_both.is_interface(&glass));
+        assert!((in_both.relative_index(&glass) - 1.5 / 1.33).abs() < 1e-6);
+    }
+
+    #[test]
+    fn test_dispersion_applies_only_to_spectral_rays() {
+        let mut prism = dielectric(1.5, 0);
+        if let Element::Sphere(ref mut sphere) = prism {
+            sphere.material.surface = SurfaceType::Refractive {
+                index: 1.5,
+                transparency: 1.0,
+                absorption: Color::black(),
+                roughness: 0.0,
+                priority: 0,
+                dispersion: Some(Dispersion::Cauchy {
+                    a: 1.5,
+                    b: 0.01,
+                    c: 0.0,
+                }),
+            };
+        }
+        assert_eq!(Media::new().relative_index(&prism), 1.5);
+        let blue = Media::with_wavelength(400.0).relative_index(&prism);
+        let red = Media::with_wavelength(700.0).relative_index(&prism);
+        assert!(blue > red && red > 1.5);
+    }
+}
@@ -0,0 +1,384 @@
+use bvh::{Aabb, Bounded, Bvh};
+use point::Point;
+use rendering::{Intersectable, Ray, TextureCoords};
+use sampling::orthonormal_basis;
+use serde::de::Error;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use std::fs::File;
+use std::io::{BufRead, BufReader};
+use std::path::{Path, PathBuf};
+use vector::Vector3;
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Face {
+    pub vertices: [Point; 3],
+    #[serde(default)]
+    pub normals: Option<[Vector3; 3]>,
+    #[serde(default)]
+    pub texture_coords: Option<[TextureCoords; 3]>,
+}
+
+impl Face {
+    pub fn geometric_normal(&self) -> Vector3 {
+        let edge1 = self.vertices[1] - self.vertices[0];
+        let edge2 = self.vertices[2] - self.vertices[0];
+        edge1.cross(&edge2).normalise()
+    }
+
+    // Weights of vertices 0, 1 and 2 for a point lying in the plane of the face.
+    pub fn barycentric(&self, point: &Point) -> (f64, f64, f64) {
+        let v0 = self.vertices[1] - self.vertices[0];
+        let v1 = self.vertices[2] - self.vertices[0];
+        let v2 = *point - self.vertices[0];
+        let d00 = v0.dot(&v0);
+        let d01 = v0.dot(&v1);
+        let d11 = v1.dot(&v1);
+        let d20 = v2.dot(&v0);
+        let d21 = v2.dot(&v1);
+        let denom = d00 * d11 - d01 * d01;
+        let v = (d11 * d20 - d01 * d21) / denom;
+        let w = (d00 * d21 - d01 * d20) / denom;
+        (1.0 - v - w, v, w)
+    }
+
+    fn distance_to(&self, point: &Point) -> f64 {
+        let (u, v, w) = self.barycentric(point);
+        let (u, v, w) = (u.max(0.0), v.max(0.0), w.max(0.0));
+        let total = u + v + w;
+        let closest = self.vertices[0]
+            + (self.vertices[1] - self.vertices[0]) * (v / total)
+            + (self.vertices[2] - self.vertices[0]) * (w / total);
+        (*point - closest).length()
+    }
+}
+
+impl Bounded for Face {
+    fn bounds(&self) -> Aabb {
+        Aabb::from_points(&self.vertices)
+    }
+}
+
+impl Intersectable for Face {
+    fn intersect(&self, ray: &Ray) -> Option<f64> {
+        let edge1 = self.vertices[1] - self.vertices[0];
+        let edge2 = self.vertices[2] - self.vertices[0];
+        let p = ray.direction.cross(&edge2);
+        let det = edge1.dot(&p);
+        if det.abs() < 1e-12 {
+            return None;
+        }
+        let inv_det = 1.0 / det;
+        let t = ray.origin - self.vertices[0];
+        let u = t.dot(&p) * inv_det;
+        if !(0.0..=1.0).contains(&u) {
+            return None;
+        }
+        let q = t.cross(&edge1);
+        let v = ray.direction.dot(&q) * inv_det;
+        if v < 0.0 || u + v > 1.0 {
+            return None;
+        }
+        let distance = edge2.dot(&q) * inv_det;
+        if distance > 0.0 {
+            Some(distance)
+        } else {
+            None
+        }
+    }
+
+    fn surface_normal(&self, hit_point: &Point) -> Vector3 {
+        match self.normals {
+            Some(ref normals) => {
+                let (u, v, w) = self.barycentric(hit_point);
+                (normals[0] * u + normals[1] * v + normals[2] * w).normalise()
+            }
+            None => self.geometric_normal(),
+        }
+    }
+
+    fn texture_coords(&self, hit_point: &Point) -> TextureCoords {
+        let (u, v, w) = self.barycentric(hit_point);
+        match self.texture_coords {
+            Some(ref coords) => TextureCoords {
+                x: coords[0].x * u as f32 + coords[1].x * v as f32 + coords[2].x * w as f32,
+                y: coords[0].y * u as f32 + coords[1].y * v as f32 + coords[2].y * w as f32,
+            },
+            None => TextureCoords {
+                x: v as f32,
+                y: w as f32,
+            },
+        }
+    }
+
+    fn tangent_frame(&self, _: &Point) -> (Vector3, Vector3) {
+        let edge1 = self.vertices[1] - self.vertices[0];
+        let edge2 = self.vertices[2] - self.vertices[0];
+        let coords = match self.texture_coords {
+            Some(ref coords) => *coords,
+            //Without texture coordinates the barycentric ones are used, running along the edges.
+            None => return (edge1, edge2),
+        };
+        let (du1, dv1) = ((coords[1].x - coords[0].x) as f64, (coords[1].y - coords[0].y) as f64);
+        let (du2, dv2) = ((coords[2].x - coords[0].x) as f64, (coords[2].y - coords[0].y) as f64);
+        let det = du1 * dv2 - du2 * dv1;
+        if det.abs() < 1e-12 {
+            return orthonormal_basis(&self.geometric_normal());
+        }
+        (
+            (edge1 * dv2 - edge2 * dv1) * (1.0 / det),
+            (edge2 * du1 - edge1 * du2) * (1.0 / det),
+        )
+    }
+}
+
+pub struct ObjModel {
+    pub path: PathBuf,
+    pub faces: Vec<Face>,
+    pub bvh: Bvh,
+}
+
+impl ObjModel {
+    pub fn load(path: &Path) -> Result<ObjModel, String> {
+        let file = File::open(path).map_err(|e| format!("{}: {}", path.display(), e))?;
+        let mut model = ObjModel::parse(BufReader::new(file))
+            .map_err(|e| format!("{}: {}", path.display(), e))?;
+        model.path = path.to_path_buf();
+        Ok(model)
+    }
+
+    pub fn parse<R: BufRead>(reader: R) -> Result<ObjModel, String> {
+        let mut positions: Vec<Point> = Vec::new();
+        let mut normals: Vec<Vector3> = Vec::new();
+        let mut uvs: Vec<TextureCoords> = Vec::new();
+        let mut faces: Vec<Face> = Vec::new();
+
+        for (number, line) in reader.lines().enumerate() {
+            let line = line.map_err(|e| e.to_string())?;
+            let location = |message: String| format!("line {}: {}", number + 1, message);
+            let mut tokens = line.split_whitespace();
+            match tokens.next() {
+                Some("v") => {
+                    let v = parse_floats(tokens, 3).map_err(&location)?;
+                    positions.push(Point {
+                        x: v[0],
+                        y: v[1],
+                        z: v[2],
+                    });
+                }
+                Some("vn") => {
+                    let v = parse_floats(tokens, 3).map_err(&location)?;
+                    normals.push(
+                        Vector3 {
+                            x: v[0],
+                            y: v[1],
+                            z: v[2],
+                        }.normalise(),
+                    );
+                }
+                Some("vt") => {
+                    let v = parse_floats(tokens, 2).map_err(&location)?;
+                    // OBJ puts the texture origin at the bottom left, images at the top left.
+                    uvs.push(TextureCoords {
+                        x: v[0] as f32,
+                        y: 1.0 - v[1] as f32,
+                    });
+                }
+                Some("f") => {
+                    let corners = tokens
+                        .map(|t| parse_corner(t, positions.len(), uvs.len(), normals.len()))
+                        .collect::<Result<Vec<_>, _>>()
+                        .map_err(&location)?;
+                    if corners.len() < 3 {
+                        return Err(location(String::from("face has fewer than 3 vertices")));
+                    }
+                    for i in 1..corners.len() - 1 {
+                        let corner = [corners[0], corners[i], corners[i + 1]];
+                        faces.push(Face {
+                            vertices: [
+                                positions[corner[0].0],
+                                positions[corner[1].0],
+                                positions[corner[2].0],
+                            ],
+                            normals: match (corner[0].2, corner[1].2, corner[2].2) {
+                                (Some(a), Some(b), Some(c)) => {
+                                    Some([normals[a], normals[b], normals[c]])
+                                }
+                                _ => None,
+                            },
+                            texture_coords: match (corner[0].1, corner[1].1, corner[2].1) {
+                                (Some(a), Some(b), Some(c)) => Some([uvs[a], uvs[b], uvs[c]]),
+                                _ => None,
+                            },
+                        });
+                    }
+                }
+                // Groups, objects, smoothing groups and materials all end up in the one mesh.
+                _ => {}
+            }
+        }
+        if faces.is_empty() {
+            return Err(String::from("model contains no faces"));
+        }
+
+        Ok(ObjModel {
+            path: PathBuf::new(),
+            bvh: Bvh::from_items(&faces),
+            faces,
+        })
+    }
+
+    pub fn intersect_face(&self, ray: &Ray) -> Option<(f64, &Face)> {
+        let faces = &self.faces;
+        self.bvh
+            .intersect(ray, |i| faces[i].intersect(ray).map(|d| (d, &faces[i])))
+    }
+
+    pub fn face_at(&self, point: &Point) -> &Face {
+        self.faces
+            .iter()
+            .min_by(|a, b| {
+                a.distance_to(point)
+                    .partial_cmp(&b.distance_to(point))
+                    .unwrap()
+            })
+            .unwrap()
+    }
+}
+
+fn parse_floats<'a, I>(tokens: I, count: usize) -> Result<Vec<f64>, String>
+where
+    I: Iterator<Item = &'a str>,
+{
+    let values = tokens
+        .take(count)
+        .map(|t| t.parse::<f64>().map_err(|e| format!("{}: {}", t, e)))
+        .collect::<Result<Vec<_>, _>>()?;
+    if values.len() < count {
+        return Err(format!("expected {} values", count));
+    }
+    Ok(values)
+}
+
+fn parse_index(token: &str, count: usize) -> Result<Option<usize>, String> {
+    if token.is_empty() {
+        return Ok(None);
+    }
+    let index = token
+        .parse::<i64>()
+        .map_err(|e| format!("{}: {}", token, e))?;
+    let resolved = if index < 0 {
+        count as i64 + index
+    } else {
+        index - 1
+    };
+    if resolved < 0 || resolved >= count as i64 {
+        return Err(format!("index {} out of range", index));
+    }
+    Ok(Some(resolved as usize))
+}
+
+fn parse_corner(
+    token: &str,
+    n_positions: usize,
+    n_uvs: usize,
+    n_normals: usize,
+) -> Result<(usize, Option<usize>, Option<usize>), String> {
+    let mut parts = token.split('/');
+    let position = parse_index(parts.next().unwrap_or(""), n_positions)?
+        .ok_or_else(|| format!("{}: missing vertex index", token))?;
+    let uv = parse_index(parts.next().unwrap_or(""), n_uvs)?;
+    let normal = parse_index(parts.next().unwrap_or(""), n_normals)?;
+    Ok((position, uv, normal))
+}
+
+pub fn load_obj<'de, D>(deserializer: D) -> Result<ObjModel, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let path = PathBuf::deserialize(deserializer)?;
+    ObjModel::load(&path).map_err(D::Error::custom)
+}
+
+pub fn write_obj_path<S>(model: &ObjModel, serializer: S) -> Result<S::Ok, S::Error>
+where
+    S: Serializer,
+{
+    model.path.serialize(serializer)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const QUAD: &str = "
+v 0 0 0
+v 1 0 0
+v 1 1 0
+v 0 1 0
+vt 0 0
+vt 1 0
+vt 1 1
+vt 0 1
+vn 0 0 1
+g front
+f 1/1/1 2/2/1 3/3/1 4/4/1
+g back
+f -1 -2 -3
+";
+
+    #[test]
+    fn test_parse_obj_triangulates_all_groups() {
+        let model = ObjModel::parse(QUAD.as_bytes()).unwrap();
+        assert_eq!(model.faces.len(), 3);
+        assert!(model.faces[0].normals.is_some());
+        assert!(model.faces[2].texture_coords.is_none());
+        assert_eq!(model.faces[2].vertices[0].y, 1.0);
+    }
+
+    #[test]
+    fn test_parse_obj_rejects_bad_index() {
+        assert!(ObjModel::parse("v 0 0 0\nf 1 2 3\n".as_bytes()).is_err());
+    }
+
+    #[test]
+    fn test_face_intersection_interpolates_texture_coords() {
+        let model = ObjModel::parse(QUAD.as_bytes()).unwrap();
+        let ray = Ray {
+            origin: Point {
+                x: 0.75,
+                y: 0.25,
+                z: 1.0,
+            },
+            direction: Vector3 {
+                x: 0.0,
+                y: 0.0,
+                z: -1.0,
+            },
+        };
+        let (distance, face) = model.intersect_face(&ray).unwrap();
+        assert!((distance - 1.0).abs() < 1e-9);
+        let hit_point = ray.origin + ray.direction * distance;
+        let coords = face.texture_coords(&hit_point);
+        assert!((coords.x - 0.75).abs() < 1e-6);
+        assert!((coords.y - 0.75).abs() < 1e-6);
+    }
+
+    #[test]
+    fn test_face_tangent_frame_follows_texture_coords() {
+        let model = ObjModel::parse(QUAD.as_bytes()).unwrap();
+        let face = &model.faces[0];
+        let hit_point = Point {
+            x: 0.6,
+            y: 0.3,
+            z: 0.0,
+        };
+        let (dp_du, dp_dv) = face.tangent_frame(&hit_point);
+        let coords = face.texture_coords(&hit_point);
+        let along_u = face.texture_coords(&(hit_point + dp_du * 0.01));
+        let along_v = face.texture_coords(&(hit_point + dp_dv * 0.01));
+        assert!((along_u.x - coords.x - 0.01).abs() < 1e-6);
+        assert!((along_u.y - coords.y).abs() < 1e-6);
+        assert!((along_v.x - coords.x).abs() < 1e-6);
+        assert!((along_v.y - coords.y - 0.01).abs() < 1e-6);
+    }
+}
@@ -0,0 +1,153 @@
+use rand::Rng;
+use sampling::orthonormal_basis;
+use scene::Color;
+use std::f64::consts::PI;
+use vector::Vector3;
+
+//Below this the highlight from a point light becomes too small to ever be sampled.
+const MIN_ALPHA: f64 = 0.002;
+
+//The glossy lobe of a Cook-Torrance BRDF with the GGX (Trowbridge-Reitz) distribution,
+//Smith shadowing and Schlick's Fresnel approximation.
+pub struct Ggx {
+    normal: Vector3,
+    alpha: f64,
+    f0: Color,
+}
+
+impl Ggx {
+    //Dielectrics reflect a grey fraction set by their index of refraction at normal incidence,
+    //metals their own colour. `normal` is turned to face `view` if necessary.
+    pub fn new(
+        normal: Vector3,
+        view: &Vector3,
+        base_color: Color,
+        roughness: f32,
+        metallic: f32,
+        ior: f32,
+    ) -> Ggx {
+        let r0 = ((ior - 1.0) / (ior + 1.0)).powi(2);
+        let metallic = metallic.clamp(0.0, 1.0);
+        Ggx {
+            normal: if normal.dot(view) < 0.0 { -normal } else { normal },
+            alpha: roughness_to_alpha(roughness),
+            f0: Color {
+                red: r0 + (base_color.red - r0) * metallic,
+                green: r0 + (base_color.green - r0) * metallic,
+                blue: r0 + (base_color.blue - r0) * metallic,
+            },
+        }
+    }
+
+    fn distribution(&self, n_dot_h: f64) -> f64 {
+        let a2 = self.alpha * self.alpha;
+        let d = n_dot_h * n_dot_h * (a2 - 1.0) + 1.0;
+        a2 / (PI * d * d)
+    }
+
+    fn shadowing(&self, n_dot_x: f64) -> f64 {
+        let a2 = self.alpha * self.alpha;
+        2.0 * n_dot_x / (n_dot_x + (a2 + (1.0 - a2) * n_dot_x * n_dot_x).sqrt())
+    }
+
+    fn fresnel(&self, cos_theta: f64) -> Color {
+        let weight = (1.0 - cos_theta).max(0.0).powi(5) as f32;
+        self.f0 * (1.0 - weight) + Color::white() * weight
+    }
+
+    //BRDF times the cosine of the light direction, for both directions pointing away from
+    //the surface.
+    pub fn evaluate(&self, view: &Vector3, light: &Vector3) -> Color {
+        let n_dot_v = self.normal.dot(view);
+        let n_dot_l = self.normal.dot(light);
+        if n_dot_v <= 0.0 || n_dot_l <= 0.0 {
+            return Color::black();
+        }
+        let half_vector = (*view + *light).normalise();
+        let n_dot_h = self.normal.dot(&half_vector);
+        let shadowing = self.shadowing(n_dot_v) * self.shadowing(n_dot_l);
+        self.fresnel(view.dot(&half_vector))
+            * (self.distribution(n_dot_h) * shadowing / (4.0 * n_dot_v)) as f32
+    }
+
+    //Reflected direction chosen in proportion to the distribution of microfacet normals,
+    //with the BRDF times cosine divided by the density of choosing it.
+    pub fn sample<R: Rng>(&self, view: &Vector3, rng: &mut R) -> Option<(Vector3, Color)> {
+        let n_dot_v = self.normal.dot(view);
+        if n_dot_v <= 0.0 {
+            return None;
+        }
+        let half_vector = sample_normal(&self.normal, self.alpha, rng);
+        let cos_theta = self.normal.dot(&half_vector);
+        let v_dot_h = view.dot(&half_vector);
+        if v_dot_h <= 0.0 {
+            return None;
+        }
+        let light = half_vector * (2.0 * v_dot_h) - *view;
+        let n_dot_l = self.normal.dot(&light);
+        if n_dot_l <= 0.0 {
+            return None;
+        }
+        let shadowing = self.shadowing(n_dot_v) * self.shadowing(n_dot_l);
+        let weight = shadowing * v_dot_h / (n_dot_v * cos_theta);
+        Some((light, self.fresnel(v_dot_h) * weight as f32))
+    }
+}
+
+pub fn roughness_to_alpha(roughness: f32) -> f64 {
+    (roughness as f64 * roughness as f64).max(MIN_ALPHA)
+}
+
+//Microfacet normal with density D(h) * cos(theta_h) for the GGX distribution of width `alpha`.
+pub fn sample_normal<R: Rng>(normal: &Vector3, alpha: f64, rng: &mut R) -> Vector3 {
+    let u = rng.gen::<f64>();
+    let phi = 2.0 * PI * rng.gen::<f64>();
+    let tan2_theta = alpha * alpha * u / (1.0 - u).max(1e-12);
+    let cos_theta = 1.0 / (1.0 + tan2_theta).sqrt();
+    let sin_theta = (1.0 - cos_theta * cos_theta).max(0.0).sqrt();
+    let (tangent, bitangent) = orthonormal_basis(normal);
+    (tangent * (sin_theta * phi.cos()) + bitangent * (sin_theta * phi.sin()) + *normal * cos_theta)
+        .normalise()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::rngs::StdRng;
+    use rand::SeedableRng;
+
+    #[test]
+    fn test_sampling_agrees_with_evaluation() {
+        let normal = Vector3 {
+            x: 0.0,
+            y: 1.0,
+            z: 0.0,
+        };
+        let view = Vector3 {
+            x: 0.6,
+            y: 0.8,
+            z: 0.0,
+        };
+        let lobe = Ggx::new(normal, &view, Color::white(), 0.5, 1.0, 1.5);
+        let mut rng = StdRng::seed_from_u64(1);
+        let n = 200_000;
+        let mut sampled = 0.0;
+        let mut uniform = 0.0;
+        for _ in 0..n {
+            if let Some((_, weight)) = lobe.sample(&view, &mut rng) {
+                sampled += weight.red as f64;
+            }
+            let u = rng.gen::<f64>();
+            let phi = 2.0 * PI * rng.gen::<f64>();
+            let r = (1.0 - u * u).sqrt();
+            let light = Vector3 {
+                x: r * phi.cos(),
+                y: u,
+                z: r * phi.sin(),
+            };
+            uniform += lobe.evaluate(&view, &light).red as f64 * 2.0 * PI;
+        }
+        let (sampled, uniform) = (sampled / n as f64, uniform / n as f64);
+        assert!((sampled - uniform).abs() < 0.02, "{} vs {}", sampled, uniform);
+    }
+}
@@ -0,0 +1,255 @@
+use bump::shading_normal;
+use point::Point;
+use rand::Rng;
+use emission::is_sampled;
+use medium::Media;
+use microfacet::Ggx;
+use rendering::{
+    consistent_normal, diffuse_color, facing, fresnel, frosted_normal, glossy_color, miss_color,
+    onto_side, specular_color, surface_color, transmittance, Ray,
+};
+use sampling::cosine_hemisphere;
+use scene::{Color, Scene, SurfaceType};
+use vector::Vector3;
+
+// Bounces that always happen before Russian roulette may end the path.
+const MIN_BOUNCES: u32 = 3;
+
+//`media` holds the dielectrics the camera starts inside, and the wavelength traced if spectral.
+pub fn trace_path<'a, R: Rng>(
+    scene: &'a Scene,
+    camera_ray: &Ray,
+    mut media: Media<'a>,
+    rng: &mut R,
+) -> Color {
+    let mut radiance = Color::black();
+    let mut throughput = Color::white();
+    let mut ray = Ray {
+        origin: camera_ray.origin,
+        direction: camera_ray.direction,
+    };
+    let mut bounce = 0;
+    //Light sampling at diffuse hits already counted whatever the next ray escapes to, and any
+    //emissive element it runs into.
+    let mut sampled_lights = false;
+
+    loop {
+        let intersection = match scene.trace(&ray) {
+            Some(intersection) => intersection,
+            None => {
+                if !sampled_lights {
+                    radiance = radiance + throughput * miss_color(scene, &ray, bounce == 0);
+                }
+                break;
+            }
+        };
+        let hit_point = ray.origin + (ray.direction * intersection.distance);
+        let surface_normal = intersection.surface.surface_normal(&hit_point);
+        let element = intersection.element;
+        throughput = throughput * transmittance(media.absorption(), intersection.distance);
+        if !media.is_interface(element) {
+            media = media.crossed(element);
+            ray = Ray {
+                origin: hit_point + (ray.direction * scene.shadow_bias),
+                direction: ray.direction,
+            };
+            continue;
+        }
+        let material = element.material();
+        let surface_color = surface_color(scene, &ray, &intersection, hit_point, surface_normal);
+        let geometric_normal = surface_normal;
+        //Lights and bounces are kept to the side of the surface the ray arrived on.
+        let side = facing(geometric_normal, ray.direction);
+        let surface_normal =
+            shading_normal(material, intersection.surface, &hit_point, surface_normal);
+        if !sampled_lights || !is_sampled(element) {
+            radiance = radiance + throughput * material.emitted();
+        }
+        radiance = radiance
+            + throughput
+                * specular_color(
+                    scene,
+                    material,
+                    hit_point,
+                    surface_normal,
+                    side,
+                    -ray.direction,
+                    rng,
+                );
+
+        let view_direction = -ray.direction;
+        let glossy = match material.surface {
+            SurfaceType::Microfacet {
+                roughness,
+                metallic,
+                ior,
+            } => {
+                let lobe = Ggx::new(
+                    surface_normal,
+                    &view_direction,
+                    surface_color,
+                    roughness,
+                    metallic,
+                    ior,
+                );
+                radiance = radiance
+                    + throughput * glossy_color(scene, &lobe, hit_point, side, view_direction, rng);
+                Some(lobe)
+            }
+            _ => None,
+        };
+
+        //The weight of the diffuse lobe, and the probability of following it.
+        let (diffuse_weight, diffuse_probability) = match material.surface {
+            SurfaceType::Diffuse => (1.0, 1.0),
+            SurfaceType::Reflective { reflectivity } => (1.0 - reflectivity, 1.0 - reflectivity),
+            SurfaceType::Refractive { .. } => (0.0, 0.0),
+            //Half the paths stay glossy even on a dielectric, whose highlights are the
+            //hardest part to find.
+            SurfaceType::Microfacet { metallic, .. } => (1.0 - metallic, 0.5 * (1.0 - metallic)),
+        };
+
+        //Pick one lobe at random, dividing its weight by the chance of picking it.
+        ray = if rng.gen::<f32>() < diffuse_probability {
+            // Facing the normal towards the ray lets diffuse meshes be lit from either side.
+            let normal = if surface_normal.dot(&side) < 0.0 {
+                -surface_normal
+            } else {
+                surface_normal
+            };
+            throughput = throughput * (diffuse_weight / diffuse_probability);
+            radiance = radiance
+                + throughput
+                    * diffuse_color(scene, material, surface_color, hit_point, normal, side, rng);
+            throughput = throughput * surface_color * material.albedo;
+            sampled_lights = true;
+            Ray {
+                origin: hit_point + (side * scene.shadow_bias),
+                direction: onto_side(cosine_hemisphere(&normal, rng), side),
+            }
+        } else {
+            sampled_lights = false;
+            match material.surface {
+                SurfaceType::Refractive {
+                    transparency,
+                    roughness,
+                    ..
+                } => {
+                    throughput = throughput * surface_color * transparency;
+                    let index = media.relative_index(element);
+                    let normal = frosted_normal(surface_normal, ray.direction, roughness, rng);
+                    let normal = consistent_normal(normal, geometric_normal, ray.direction);
+                    let kr = fresnel(ray.direction, normal, index);
+                    let transmission = if rng.gen::<f64>() < kr {
+                        None
+                    } else {
+                        Ray::create_transmission(
+                            normal,
+                            geometric_normal,
+                            ray.direction,
+                            hit_point,
+                            scene.shadow_bias,
+                            index,
+                        )
+                    };
+                    match transmission {
+                        Some(transmission_ray) => {
+                            media = media.crossed(element);
+                            transmission_ray
+                        }
+                        None => reflect(&ray, normal, geometric_normal, hit_point, scene),
+                    }
+                }
+                SurfaceType::Microfacet { .. } => {
+                    let lobe = glossy.unwrap();
+                    //Reflections the shading normal sends below the surface are lost.
+                    let (direction, weight) = match lobe.sample(&view_direction, rng) {
+                        Some(sample) if sample.0.dot(&side) > 0.0 => sample,
+                        _ => break,
+                    };
+                    throughput = throughput * weight * (1.0 / (1.0 - diffuse_probability));
+                    Ray {
+                        origin: hit_point + (side * scene.shadow_bias),
+                        direction,
+                    }
+                }
+                _ => reflect(&ray, surface_normal, geometric_normal, hit_point, scene),
+            }
+        };
+
+        bounce += 1;
+        if bounce > MIN_BOUNCES {
+            let survival = throughput.max_component().min(0.95);
+            if rng.gen::<f32>() >= survival {
+                break;
+            }
+            throughput = throughput * (1.0 / survival);
+        }
+    }
+    radiance
+}
+
+fn reflect(
+    ray: &Ray,
+    surface_normal: Vector3,
+    geometric_normal: Vector3,
+    hit_point: Point,
+    scene: &Scene,
+) -> Ray {
+    Ray::create_reflection(
+        surface_normal,
+        geometric_normal,
+        ray.direction,
+        hit_point,
+        scene.shadow_bias,
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::rngs::StdRng;
+    use rand::SeedableRng;
+
+    #[test]
+    fn test_sampled_emitters_are_not_counted_again_when_hit() {
+        //A white floor lit only by a glowing sphere that reflects nothing, so everything a path
+        //gathers is the sphere's light arriving directly at the floor.
+        let scene = Scene::with(
+            r#"[{"Plane": {"origin": {"x": 0.0, "y": -1.0, "z": 0.0},
+                    "normal": {"x": 0.0, "y": -1.0, "z": 0.0},
+                    "material": {"coloration": {"Color": {"red": 1.0, "green": 1.0, "blue": 1.0}},
+                                 "albedo": 1.0, "surface": "Diffuse"}}},
+                {"Sphere": {"centre": {"x": 0.0, "y": 0.0, "z": -1.0}, "radius": 0.5,
+                    "material": {"coloration": {"Color": {"red": 0.0, "green": 0.0, "blue": 0.0}},
+                                 "albedo": 0.0, "surface": "Diffuse",
+                                 "emission": {"color": {"red": 1.0, "green": 1.0, "blue": 1.0},
+                                              "strength": 5.0}}}}]"#,
+            "[]",
+        );
+        let floor = scene.elements[0].material();
+        let hit_point = Point {
+            x: 0.0,
+            y: -1.0,
+            z: -1.5,
+        };
+        let up = Vector3 {
+            x: 0.0,
+            y: 1.0,
+            z: 0.0,
+        };
+        let camera_ray = Ray {
+            origin: Point::zero(),
+            direction: (hit_point - Point::zero()).normalise(),
+        };
+        let n = 2000;
+        let mut rng = StdRng::seed_from_u64(0);
+        let (mut direct, mut path) = (0.0, 0.0);
+        for _ in 0..n {
+            direct += diffuse_color(&scene, floor, Color::white(), hit_point, up, up, &mut rng).red;
+            path += trace_path(&scene, &camera_ray, Media::new(), &mut rng).red;
+        }
+        assert!(direct > 0.0);
+        assert!((path / direct - 1.0).abs() < 0.05);
+    }
+}
@@ -0,0 +1,354 @@
+use point::Point;
+use rendering::TextureCoords;
+use scene::Color;
+use serde::{Deserialize, Deserializer};
+use std::f64::consts::PI;
+
+//Where a pattern is evaluated: in the element's texture coordinates, or at the hit point itself
+//so that it runs through an object like a solid block of material.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default)]
+pub enum Space {
+    #[default]
+    Uv,
+    World,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub enum Pattern {
+    //Cubes of side 1 cycling through the colours.
+    Checker,
+    //Fractal Brownian motion: octaves of Perlin noise, each twice the frequency and half the
+    //amplitude of the last.
+    Noise {
+        #[serde(default = "default_octaves")]
+        octaves: u32,
+    },
+    //Bands along x, pushed about by turbulence.
+    Marble {
+        #[serde(default = "default_octaves")]
+        octaves: u32,
+        #[serde(default = "default_marble_turbulence")]
+        turbulence: f64,
+    },
+    //Rings of radius 1 around the z axis, distorted by noise.
+    Wood {
+        #[serde(default = "default_octaves")]
+        octaves: u32,
+        #[serde(default = "default_wood_turbulence")]
+        turbulence: f64,
+    },
+    //The colours laid out from 0 to 1 along x (or u).
+    Ramp,
+}
+
+fn default_octaves() -> u32 {
+    4
+}
+
+fn default_marble_turbulence() -> f64 {
+    5.0
+}
+
+fn default_wood_turbulence() -> f64 {
+    0.1
+}
+
+fn default_scale() -> f64 {
+    1.0
+}
+
+//A colour, optionally pinned to a position along the ramp; unpinned stops are spread evenly.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(untagged)]
+pub enum ColorStop {
+    At { position: f32, color: Color },
+    Color(Color),
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Procedural {
+    pub pattern: Pattern,
+    //Pattern repeats per unit of texture coordinate or world distance.
+    #[serde(default = "default_scale")]
+    pub scale: f64,
+    #[serde(default)]
+    pub space: Space,
+    pub colors: Vec<ColorStop>,
+}
+
+impl Procedural {
+    //A pattern needs at least two colours to tell its parts apart.
+    pub fn deserialize_checked<'de, D>(deserializer: D) -> Result<Procedural, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        use serde::de::Error;
+        let procedural = Procedural::deserialize(deserializer)?;
+        if procedural.colors.len() < 2 {
+            return Err(D::Error::custom(
+                "a procedural coloration needs at least two colors",
+            ));
+        }
+        Ok(procedural)
+    }
+
+    pub fn color(&self, texture_coords: &TextureCoords, position: &Point) -> Color {
+        let p = match self.space {
+            Space::Uv => Point {
+                x: texture_coords.x as f64,
+                y: texture_coords.y as f64,
+                z: 0.0,
+            },
+            Space::World => *position,
+        };
+        let p = Point {
+            x: p.x * self.scale,
+            y: p.y * self.scale,
+            z: p.z * self.scale,
+        };
+        let t = match self.pattern {
+            Pattern::Checker => {
+                let cell = p.x.floor() + p.y.floor() + p.z.floor();
+                let n = self.colors.len().max(1) as f64;
+                return self.stop(((cell % n + n) % n) as usize).1;
+            }
+            Pattern::Noise { octaves } => 0.5 + 0.5 * fbm(&p, octaves),
+            Pattern::Marble {
+                octaves,
+                turbulence: strength,
+            } => 0.5 + 0.5 * ((p.x + strength * turbulence(&p, octaves)) * PI).sin(),
+            Pattern::Wood {
+                octaves,
+                turbulence: strength,
+            } => {
+                let radius = (p.x * p.x + p.y * p.y).sqrt() + strength * fbm(&p, octaves);
+                radius - radius.floor()
+            }
+            Pattern::Ramp => p.x,
+        };
+        self.ramp(t as f32)
+    }
+
+    fn stop(&self, i: usize) -> (f32, Color) {
+        match self.colors.get(i) {
+            Some(&ColorStop::At { position, color }) => (position, color),
+            Some(&ColorStop::Color(color)) => {
+                let last = self.colors.len().max(2) - 1;
+                (i as f32 / last as f32, color)
+            }
+            None => (0.0, Color::black()),
+        }
+    }
+
+    //Linear interpolation between the stops either side of t, holding the end colours beyond.
+    fn ramp(&self, t: f32) -> Color {
+        let n = self.colors.len();
+        if n == 0 {
+            return Color::black();
+        }
+        let (mut low, mut high) = (self.stop(0), self.stop(n - 1));
+        if t <= low.0 {
+            return low.1;
+        }
+        if t >= high.0 {
+            return high.1;
+        }
+        for i in 1..n {
+            let stop = self.stop(i);
+            if stop.0 >= t {
+                high = stop;
+                break;
+            }
+            low = stop;
+        }
+        let span = high.0 - low.0;
+        if span <= 0.0 {
+            return high.1;
+        }
+        let f = (t - low.0) / span;
+        low.1 * (1.0 - f) + high.1 * f
+    }
+}
+
+//A fixed hash standing in for Perlin's permutation table.
+fn hash(x: i64, y: i64, z: i64) -> u32 {
+    let mut h = (x.wrapping_mul(73_856_093) ^ y.wrapping_mul(19_349_663) ^ z.wrapping_mul(83_492_791))
+        as u32;
+    h ^= h >> 13;
+    h = h.wrapping_mul(0x5bd1_e995);
+    h ^ (h >> 15)
+}
+
+//Dot product of the offset with one of the twelve edge directions of a cube.
+fn gradient(hash: u32, x: f64, y: f64, z: f64) -> f64 {
+    match hash % 12 {
+        0 => x + y,
+        1 => -x + y,
+        2 => x - y,
+        3 => -x - y,
+        4 => x + z,
+        5 => -x + z,
+        6 => x - z,
+        7 => -x - z,
+        8 => y + z,
+        9 => -y + z,
+        10 => y - z,
+        _ => -y - z,
+    }
+}
+
+fn fade(t: f64) -> f64 {
+    t * t * t * (t * (t * 6.0 - 15.0) + 10.0)
+}
+
+fn lerp(t: f64, a: f64, b: f64) -> f64 {
+    a + t * (b - a)
+}
+
+//Perlin's improved noise, roughly in [-1, 1] and zero at every lattice point.
+pub fn perlin(p: &Point) -> f64 {
+    let (xi, yi, zi) = (p.x.floor(), p.y.floor(), p.z.floor());
+    let (x, y, z) = (p.x - xi, p.y - yi, p.z - zi);
+    let (xi, yi, zi) = (xi as i64, yi as i64, zi as i64);
+    let corner = |dx: i64, dy: i64, dz: i64| {
+        gradient(
+            hash(xi + dx, yi + dy, zi + dz),
+            x - dx as f64,
+            y - dy as f64,
+            z - dz as f64,
+        )
+    };
+    let (u, v, w) = (fade(x), fade(y), fade(z));
+    lerp(
+        w,
+        lerp(
+            v,
+            lerp(u, corner(0, 0, 0), corner(1, 0, 0)),
+            lerp(u, corner(0, 1, 0), corner(1, 1, 0)),
+        ),
+        lerp(
+            v,
+            lerp(u, corner(0, 0, 1), corner(1, 0, 1)),
+            lerp(u, corner(0, 1, 1), corner(1, 1, 1)),
+        ),
+    )
+}
+
+fn octaves<F: Fn(f64) -> f64>(p: &Point, octaves: u32, shape: F) -> f64 {
+    let mut total = 0.0;
+    let mut amplitude = 1.0;
+    let mut frequency = 1.0;
+    let mut norm = 0.0;
+    for _ in 0..octaves.max(1) {
+        let q = Point {
+            x: p.x * frequency,
+            y: p.y * frequency,
+            z: p.z * frequency,
+        };
+        total += amplitude * shape(perlin(&q));
+        norm += amplitude;
+        amplitude *= 0.5;
+        frequency *= 2.0;
+    }
+    total / norm
+}
+
+pub fn fbm(p: &Point, n: u32) -> f64 {
+    octaves(p, n, |x| x)
+}
+
+//Like `fbm` but folding each octave to be positive, giving the creases marble veins follow.
+pub fn turbulence(p: &Point, n: u32) -> f64 {
+    octaves(p, n, f64::abs)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use scene::Coloration;
+    use serde_json;
+
+    fn coords(x: f32, y: f32) -> TextureCoords {
+        TextureCoords { x, y }
+    }
+
+    fn grey(value: f32) -> Color {
+        Color {
+            red: value,
+            green: value,
+            blue: value,
+        }
+    }
+
+    #[test]
+    fn test_checker_alternates() {
+        let checker = Procedural {
+            pattern: Pattern::Checker,
+            scale: 2.0,
+            space: Space::Uv,
+            colors: vec![ColorStop::Color(grey(0.0)), ColorStop::Color(grey(1.0))],
+        };
+        let origin = Point::zero();
+        assert_eq!(checker.color(&coords(0.1, 0.1), &origin).red, 0.0);
+        assert_eq!(checker.color(&coords(0.6, 0.1), &origin).red, 1.0);
+        assert_eq!(checker.color(&coords(0.6, 0.6), &origin).red, 0.0);
+        assert_eq!(checker.color(&coords(-0.1, 0.1), &origin).red, 1.0);
+    }
+
+    #[test]
+    fn test_ramp_interpolates_between_stops() {
+        let ramp = Procedural {
+            pattern: Pattern::Ramp,
+            scale: 1.0,
+            space: Space::World,
+            colors: vec![
+                ColorStop::Color(grey(0.0)),
+                ColorStop::At {
+                    position: 0.25,
+                    color: grey(1.0),
+                },
+                ColorStop::Color(grey(0.0)),
+            ],
+        };
+        let at = |x: f64| {
+            ramp.color(&coords(0.0, 0.0), &Point { x, y: 0.0, z: 0.0 })
+                .red
+        };
+        assert!((at(0.125) - 0.5).abs() < 1e-6);
+        assert!((at(0.25) - 1.0).abs() < 1e-6);
+        assert!((at(0.625) - 0.5).abs() < 1e-6);
+        assert_eq!(at(2.0), 0.0);
+    }
+
+    #[test]
+    fn test_fewer_than_two_colors_is_rejected() {
+        let coloration = |colors: &str| {
+            serde_json::from_str::<Coloration>(&format!(
+                r#"{{"Procedural": {{"pattern": "Checker", "colors": {}}}}}"#,
+                colors
+            ))
+        };
+        let white = r#"{"red": 1.0, "green": 1.0, "blue": 1.0}"#;
+        for colors in &["[]".to_string(), format!("[{}]", white)] {
+            let error = coloration(colors).err().unwrap();
+            assert!(error
+                .to_string()
+                .starts_with("a procedural coloration needs at least two colors"));
+        }
+        assert!(coloration(&format!("[{}, {}]", white, white)).is_ok());
+    }
+
+    #[test]
+    fn test_noise_is_bounded_and_zero_on_lattice() {
+        assert_eq!(perlin(&Point { x: 3.0, y: -2.0, z: 7.0 }), 0.0);
+        for i in 0..1000 {
+            let p = Point {
+                x: i as f64 * 0.137,
+                y: i as f64 * 0.071,
+                z: i as f64 * -0.093,
+            };
+            assert!(fbm(&p, 5).abs() <= 1.0);
+            assert!(turbulence(&p, 5) >= 0.0);
+        }
+    }
+}
@@ -1,6 +1,14 @@
+use bump::shading_normal;
 use matrix::Matrix33;
+use medium::Media;
+use microfacet::{roughness_to_alpha, sample_normal, Ggx};
 use point::Point;
-use scene::{Color, Disk, Element, Intersection, Plane, Scene, Sphere, SurfaceType};
+use rand::Rng;
+use sampling::{concentric_disk, orthonormal_basis};
+use scene::{
+    Color, Disk, Element, Intersection, LightSample, Material, Mesh, Plane, Scene, Sphere,
+    SurfaceType, Triangle,
+};
 use std::f32;
 use std::f32::consts::PI;
 use vector::Vector3;
@@ -11,10 +19,8 @@ pub struct Ray {
 }
 
 impl Ray {
-    pub fn create_prime(x: f32, y: f32, scene: &Scene) -> Ray {
-        assert!(scene.width > scene.height);
-        let fov_adjustment = fov_factor(&scene.fov);
-        let aspect_ratio = (scene.width as f64) / (scene.height as f64);
+    pub fn create_prime<R: Rng>(x: f32, y: f32, scene: &Scene, rng: &mut R) -> Ray {
+        let (half_width, half_height) = sensor_size(scene);
 
         let normalised_device_coord_x = (x as f64 + 0.5) / scene.width as f64;
         let normalised_device_coord_y = (y as f64 + 0.5) / scene.height as f64;
@@ -22,8 +28,8 @@ impl Ray {
         let screen_coord_x = normalised_device_coord_x * 2.0 - 1.0;
         let screen_coord_y = 1.0 - normalised_device_coord_y * 2.0;
 
-        let sensor_x = (screen_coord_x * aspect_ratio) * fov_adjustment;
-        let sensor_y = screen_coord_y * fov_adjustment;
+        let sensor_x = screen_coord_x * half_width;
+        let sensor_y = screen_coord_y * half_height;
 
         let direction = Vector3 {
             x: sensor_x,
@@ -31,27 +37,51 @@ impl Ray {
             z: -1.0,
         };
 
+        let camera = &scene.camera;
+        if camera.aperture <= 0.0 {
+            return Ray {
+                origin: camera.position,
+                direction: (camera.rotation_matrix * direction).normalise(),
+            };
+        }
+
+        // Thin lens: every ray through the lens meets the pinhole ray on the focal plane.
+        let focal_point = direction * camera.focus_distance();
+        let (lens_x, lens_y) = concentric_disk(rng);
+        let lens_point = Vector3 {
+            x: lens_x * camera.aperture * 0.5,
+            y: lens_y * camera.aperture * 0.5,
+            z: 0.0,
+        };
         Ray {
-            origin: scene.camera.position,
-            direction: (scene.camera.rotation_matrix * direction).normalise(),
+            origin: camera.position + (camera.rotation_matrix * lens_point),
+            direction: (camera.rotation_matrix * (focal_point - lens_point)).normalise(),
         }
     }
 
+    //`surface_normal` may be a shading normal; `geometric_normal` is the surface's own, which the
+    //ray is kept on the near side of.
     pub fn create_reflection(
         surface_normal: Vector3,
+        geometric_normal: Vector3,
         incident_direction: Vector3,
         surface_intersection: Point,
         shadow_bias: f64,
     ) -> Ray {
+        let side = facing(geometric_normal, incident_direction);
+        let direction = incident_direction
+            - (2.0 * incident_direction.dot(&surface_normal) * surface_normal);
         Ray {
-            origin: surface_intersection + (surface_normal * shadow_bias),
-            direction: incident_direction
-                - (2.0 * incident_direction.dot(&surface_normal) * surface_normal),
+            origin: surface_intersection + (side * shadow_bias),
+            direction: onto_side(direction, side),
         }
     }
 
+    //`normal` should come from `consistent_normal`, so that it agrees with `geometric_normal`
+    //about which side the ray is on. The ray is kept on the far side of the geometric surface.
     pub fn create_transmission(
         normal: Vector3,
+        geometric_normal: Vector3,
         incident: Vector3,
         surface_intersection: Point,
         shadow_bias: f64,
@@ -74,18 +104,66 @@ impl Ray {
         if k < 0.0 {
             None
         } else {
+            let far_side = -facing(geometric_normal, incident);
             Some(Ray {
-                origin: surface_intersection + (ref_normal * -shadow_bias),
-                direction: (incident + ref_normal * i_dot_n) * eta - ref_normal * k.sqrt(),
+                origin: surface_intersection + (far_side * shadow_bias),
+                direction: onto_side(
+                    (incident + ref_normal * i_dot_n) * eta - ref_normal * k.sqrt(),
+                    far_side,
+                ),
             })
         }
     }
 }
 
+//`normal` turned, if need be, to face back against `direction`.
+pub fn facing(normal: Vector3, direction: Vector3) -> Vector3 {
+    if normal.dot(&direction) > 0.0 {
+        -normal
+    } else {
+        normal
+    }
+}
+
+//`direction` mirrored in the surface if it points to the other side of it from `side`. Shading
+//normals can tip reflections and bounces below the surface, and refractions above it.
+pub fn onto_side(direction: Vector3, side: Vector3) -> Vector3 {
+    let height = direction.dot(&side);
+    if height >= 0.0 {
+        direction
+    } else {
+        direction - side * (2.0 * height)
+    }
+}
+
+//The shading normal if the ray meets it from the same side as the geometric surface, else the
+//geometric normal, so that `fresnel` and `Ray::create_transmission` agree with the geometry
+//about whether the ray is entering or leaving.
+pub fn consistent_normal(shading: Vector3, geometric: Vector3, incident: Vector3) -> Vector3 {
+    if shading.dot(&incident) * geometric.dot(&incident) > 0.0 {
+        shading
+    } else {
+        geometric
+    }
+}
+
 fn fov_factor(fov: &f64) -> f64 {
     (fov.to_radians() / 2.0).tan()
 }
 
+//Half the width and height of the image plane one unit in front of the camera. The field of view
+//spans the shorter side, so it's vertical for landscape images and horizontal for portrait ones.
+pub fn sensor_size(scene: &Scene) -> (f64, f64) {
+    let fov_adjustment = fov_factor(&scene.fov);
+    let aspect_ratio = (scene.width as f64) / (scene.height as f64);
+    if aspect_ratio >= 1.0 {
+        (aspect_ratio * fov_adjustment, fov_adjustment)
+    } else {
+        (fov_adjustment, fov_adjustment / aspect_ratio)
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug)]
 pub struct TextureCoords {
     pub x: f32,
     pub y: f32,
@@ -95,6 +173,9 @@ pub trait Intersectable {
     fn intersect(&self, ray: &Ray) -> Option<f64>;
     fn surface_normal(&self, hit_point: &Point) -> Vector3;
     fn texture_coords(&self, hit_point: &Point) -> TextureCoords;
+    //How the hit point moves as each texture coordinate increases: the tangent and bitangent
+    //that normal and bump maps are laid along, unnormalised.
+    fn tangent_frame(&self, hit_point: &Point) -> (Vector3, Vector3);
 }
 
 impl Intersectable for Element {
@@ -103,6 +184,8 @@ impl Intersectable for Element {
             Element::Sphere(ref s) => s.intersect(ray),
             Element::Plane(ref p) => p.intersect(ray),
             Element::Disk(ref d) => d.intersect(ray),
+            Element::Triangle(ref t) => t.intersect(ray),
+            Element::Mesh(ref m) => m.intersect(ray),
         }
     }
 
@@ -111,6 +194,8 @@ impl Intersectable for Element {
             Element::Sphere(ref s) => s.surface_normal(hit_point),
             Element::Plane(ref p) => p.surface_normal(hit_point),
             Element::Disk(ref d) => d.surface_normal(hit_point),
+            Element::Triangle(ref t) => t.surface_normal(hit_point),
+            Element::Mesh(ref m) => m.surface_normal(hit_point),
         }
     }
 
@@ -119,6 +204,18 @@ impl Intersectable for Element {
             Element::Sphere(ref s) => s.texture_coords(hit_point),
             Element::Plane(ref p) => p.texture_coords(hit_point),
             Element::Disk(ref d) => d.texture_coords(hit_point),
+            Element::Triangle(ref t) => t.texture_coords(hit_point),
+            Element::Mesh(ref m) => m.texture_coords(hit_point),
+        }
+    }
+
+    fn tangent_frame(&self, hit_point: &Point) -> (Vector3, Vector3) {
+        match *self {
+            Element::Sphere(ref s) => s.tangent_frame(hit_point),
+            Element::Plane(ref p) => p.tangent_frame(hit_point),
+            Element::Disk(ref d) => d.tangent_frame(hit_point),
+            Element::Triangle(ref t) => t.tangent_frame(hit_point),
+            Element::Mesh(ref m) => m.tangent_frame(hit_point),
         }
     }
 }
@@ -161,20 +258,69 @@ impl Intersectable for Sphere {
             y: (hit_vec.y / self.radius).acos() as f32 / f32::consts::PI,
         }
     }
+
+    fn tangent_frame(&self, hit_point: &Point) -> (Vector3, Vector3) {
+        let hit_vec = *hit_point - self.centre;
+        let around = Vector3 {
+            x: -hit_vec.z,
+            y: 0.0,
+            z: hit_vec.x,
+        };
+        //At the poles u is undefined, so any direction across the pole will do.
+        let around = if around.length() > 1e-9 * self.radius {
+            around
+        } else {
+            orthonormal_basis(&hit_vec.normalise()).0 * self.radius
+        };
+        let pi = ::std::f64::consts::PI;
+        let down = hit_vec.normalise().cross(&around.normalise()) * (self.radius * pi);
+        (around * (2.0 * pi), down)
+    }
+}
+
+//Directions of increasing texture x and y across a plane; `texture_coords` measures the hit point
+//along each, so both are scaled down by their length to give the tangent frame.
+fn plane_axes(normal: &Vector3) -> (Vector3, Vector3) {
+    let mut x_axis = normal.cross(&Vector3 {
+        x: 0.0,
+        y: 0.0,
+        z: 1.0,
+    });
+    if x_axis.length() == 0.0 {
+        x_axis = normal.cross(&Vector3 {
+            x: 0.0,
+            y: 1.0,
+            z: 0.0,
+        });
+    }
+    let y_axis = normal.cross(&x_axis);
+    (x_axis, y_axis)
+}
+
+fn plane_tangent_frame(normal: &Vector3) -> (Vector3, Vector3) {
+    let (x_axis, y_axis) = plane_axes(normal);
+    (
+        x_axis * (1.0 / x_axis.dot(&x_axis)),
+        y_axis * (1.0 / y_axis.dot(&y_axis)),
+    )
+}
+
+//Distance to the plane through `origin`, hit only from the side `normal` points away from.
+fn plane_intersection(origin: &Point, normal: &Vector3, ray: &Ray) -> Option<f64> {
+    let denom = normal.dot(&ray.direction);
+    if denom > 1e-6 {
+        let v = *origin - ray.origin;
+        let distance = v.dot(normal) / denom;
+        if distance >= 0.0 {
+            return Some(distance);
+        }
+    }
+    None
 }
 
 impl Intersectable for Plane {
     fn intersect(&self, ray: &Ray) -> Option<f64> {
-        let normal = &self.normal;
-        let denom = normal.dot(&ray.direction);
-        if denom > 1e-6 {
-            let v = self.origin - ray.origin;
-            let distance = v.dot(&normal) / denom;
-            if distance >= 0.0 {
-                return Some(distance);
-            }
-        }
-        None
+        plane_intersection(&self.origin, &self.normal, ray)
     }
 
     fn surface_normal(&self, _: &Point) -> Vector3 {
@@ -182,19 +328,7 @@ impl Intersectable for Plane {
     }
 
     fn texture_coords(&self, hit_point: &Point) -> TextureCoords {
-        let mut x_axis = self.normal.cross(&Vector3 {
-            x: 0.0,
-            y: 0.0,
-            z: 1.0,
-        });
-        if x_axis.length() == 0.0 {
-            x_axis = self.normal.cross(&Vector3 {
-                x: 0.0,
-                y: 1.0,
-                z: 0.0,
-            });
-        }
-        let y_axis = self.normal.cross(&x_axis);
+        let (x_axis, y_axis) = plane_axes(&self.normal);
         let hit_vec = *hit_point - self.origin;
 
         TextureCoords {
@@ -202,16 +336,15 @@ impl Intersectable for Plane {
             y: hit_vec.dot(&y_axis) as f32,
         }
     }
+
+    fn tangent_frame(&self, _: &Point) -> (Vector3, Vector3) {
+        plane_tangent_frame(&self.normal)
+    }
 }
 
 impl Intersectable for Disk {
     fn intersect(&self, ray: &Ray) -> Option<f64> {
-        let plane = Plane {
-            origin: self.origin,
-            normal: self.normal,
-            material: self.material.clone(),
-        };
-        let plane_intersection = plane.intersect(ray);
+        let plane_intersection = plane_intersection(&self.origin, &self.normal, ray);
         if plane_intersection.is_some() {
             let v = (ray.origin + (ray.direction * plane_intersection.unwrap())) - self.origin;
             if v.dot(&v) < (self.radius * self.radius) {
@@ -229,19 +362,7 @@ impl Intersectable for Disk {
     }
 
     fn texture_coords(&self, hit_point: &Point) -> TextureCoords {
-        let mut x_axis = self.normal.cross(&Vector3 {
-            x: 0.0,
-            y: 0.0,
-            z: 1.0,
-        });
-        if x_axis.length() == 0.0 {
-            x_axis = self.normal.cross(&Vector3 {
-                x: 0.0,
-                y: 1.0,
-                z: 0.0,
-            });
-        }
-        let y_axis = self.normal.cross(&x_axis);
+        let (x_axis, y_axis) = plane_axes(&self.normal);
         let hit_vec = *hit_point - self.origin;
 
         TextureCoords {
@@ -249,97 +370,456 @@ impl Intersectable for Disk {
             y: hit_vec.dot(&y_axis) as f32,
         }
     }
+
+    fn tangent_frame(&self, _: &Point) -> (Vector3, Vector3) {
+        plane_tangent_frame(&self.normal)
+    }
+}
+
+impl Intersectable for Triangle {
+    fn intersect(&self, ray: &Ray) -> Option<f64> {
+        self.face.intersect(ray)
+    }
+
+    fn surface_normal(&self, hit_point: &Point) -> Vector3 {
+        self.face.surface_normal(hit_point)
+    }
+
+    fn texture_coords(&self, hit_point: &Point) -> TextureCoords {
+        self.face.texture_coords(hit_point)
+    }
+
+    fn tangent_frame(&self, hit_point: &Point) -> (Vector3, Vector3) {
+        self.face.tangent_frame(hit_point)
+    }
+}
+
+impl Intersectable for Mesh {
+    fn intersect(&self, ray: &Ray) -> Option<f64> {
+        self.model.intersect_face(ray).map(|(d, _)| d)
+    }
+
+    fn surface_normal(&self, hit_point: &Point) -> Vector3 {
+        self.model.face_at(hit_point).surface_normal(hit_point)
+    }
+
+    fn texture_coords(&self, hit_point: &Point) -> TextureCoords {
+        self.model.face_at(hit_point).texture_coords(hit_point)
+    }
+
+    fn tangent_frame(&self, hit_point: &Point) -> (Vector3, Vector3) {
+        self.model.face_at(hit_point).tangent_frame(hit_point)
+    }
 }
 
-pub fn cast_ray(scene: &Scene, ray: &Ray, depth: u32) -> Color {
+pub fn cast_ray<'a, R: Rng>(
+    scene: &'a Scene,
+    ray: &Ray,
+    depth: u32,
+    media: &Media<'a>,
+    rng: &mut R,
+) -> Color {
     if depth >= scene.max_recursion_depth {
         return Color::black();
     }
 
     let intersection = scene.trace(&ray);
-    intersection
-        .map(|i| get_color(&scene, &ray, &i, depth))
-        .unwrap_or(Color::black())
+    match intersection {
+        Some(i) => {
+            get_color(&scene, &ray, &i, depth, media, rng)
+                * transmittance(media.absorption(), i.distance)
+        }
+        None => miss_color(scene, ray, depth == 0),
+    }
 }
 
-fn get_color(scene: &Scene, ray: &Ray, intersection: &Intersection, depth: u32) -> Color {
+fn get_color<'a, R: Rng>(
+    scene: &'a Scene,
+    ray: &Ray,
+    intersection: &Intersection<'a>,
+    depth: u32,
+    media: &Media<'a>,
+    rng: &mut R,
+) -> Color {
     let hit_point = ray.origin + (ray.direction * intersection.distance);
-    let surface_normal = intersection.element.surface_normal(&hit_point);
+    let surface_normal = intersection.surface.surface_normal(&hit_point);
 
-    let material = intersection.element.material();
-    match material.surface {
-        SurfaceType::Diffuse => {
-            diffuse_color(scene, intersection.element, hit_point, surface_normal)
-        }
+    let element = intersection.element;
+    if !media.is_interface(element) {
+        let continued_ray = Ray {
+            origin: hit_point + (ray.direction * scene.shadow_bias),
+            direction: ray.direction,
+        };
+        return cast_ray(scene, &continued_ray, depth, &media.crossed(element), rng);
+    }
+    let material = element.material();
+    let surface_color = surface_color(scene, ray, intersection, hit_point, surface_normal);
+    let geometric_normal = surface_normal;
+    //Lights are only seen from the side of the surface the ray arrived on.
+    let side = facing(geometric_normal, ray.direction);
+    let surface_normal = shading_normal(material, intersection.surface, &hit_point, surface_normal);
+    let highlight = specular_color(
+        scene,
+        material,
+        hit_point,
+        surface_normal,
+        side,
+        -ray.direction,
+        rng,
+    );
+    material.emitted() + highlight + match material.surface {
+        SurfaceType::Diffuse => diffuse_color(
+            scene,
+            material,
+            surface_color,
+            hit_point,
+            surface_normal,
+            side,
+            rng,
+        ),
         SurfaceType::Reflective { reflectivity } => {
-            let mut color = diffuse_color(scene, intersection.element, hit_point, surface_normal);
-            let reflection_ray =
-                Ray::create_reflection(surface_normal, ray.direction, hit_point, scene.shadow_bias);
+            let mut color = diffuse_color(
+                scene,
+                material,
+                surface_color,
+                hit_point,
+                surface_normal,
+                side,
+                rng,
+            );
+            let reflection_ray = Ray::create_reflection(
+                surface_normal,
+                geometric_normal,
+                ray.direction,
+                hit_point,
+                scene.shadow_bias,
+            );
             color = color * (1.0 - reflectivity);
-            color + (cast_ray(scene, &reflection_ray, depth + 1) * reflectivity)
+            color + (cast_ray(scene, &reflection_ray, depth + 1, media, rng) * reflectivity)
         }
         SurfaceType::Refractive {
-            index,
             transparency,
+            roughness,
+            ..
         } => {
+            let index = media.relative_index(element);
+            let normal = frosted_normal(surface_normal, ray.direction, roughness, rng);
+            let normal = consistent_normal(normal, geometric_normal, ray.direction);
             let mut refraction_color = Color::black();
-            let kr = fresnel(ray.direction, surface_normal, index);
-            let surface_color = material
-                .coloration
-                .color(&intersection.element.texture_coords(&hit_point));
+            let kr = fresnel(ray.direction, normal, index);
 
             if kr < 1.0 {
-                let transmission_ray = Ray::create_transmission(
-                    surface_normal,
+                if let Some(transmission_ray) = Ray::create_transmission(
+                    normal,
+                    geometric_normal,
                     ray.direction,
                     hit_point,
                     scene.shadow_bias,
                     index,
-                ).unwrap();
-                refraction_color = cast_ray(scene, &transmission_ray, depth + 1);
+                ) {
+                    let media = media.crossed(element);
+                    refraction_color = cast_ray(scene, &transmission_ray, depth + 1, &media, rng);
+                }
             }
 
-            let reflection_ray =
-                Ray::create_reflection(surface_normal, ray.direction, hit_point, scene.shadow_bias);
-            let reflection_color = cast_ray(scene, &reflection_ray, depth + 1);
+            let reflection_ray = Ray::create_reflection(
+                normal,
+                geometric_normal,
+                ray.direction,
+                hit_point,
+                scene.shadow_bias,
+            );
+            let reflection_color = cast_ray(scene, &reflection_ray, depth + 1, media, rng);
             let mut color = reflection_color * kr + refraction_color * (1.0 - kr);
             color = color * transparency * surface_color;
             color
         }
+        SurfaceType::Microfacet {
+            roughness,
+            metallic,
+            ior,
+        } => {
+            let view_direction = -ray.direction;
+            let lobe = Ggx::new(
+                surface_normal,
+                &view_direction,
+                surface_color,
+                roughness,
+                metallic,
+                ior,
+            );
+            let diffuse = diffuse_color(
+                scene,
+                material,
+                surface_color,
+                hit_point,
+                surface_normal,
+                side,
+                rng,
+            );
+            //One glossy reflection per camera sample; the pixel's samples average them out.
+            let reflection = match lobe.sample(&view_direction, rng) {
+                Some((direction, weight)) if direction.dot(&side) > 0.0 => {
+                    let reflection_ray = Ray {
+                        origin: hit_point + (direction * scene.shadow_bias),
+                        direction,
+                    };
+                    cast_ray(scene, &reflection_ray, depth + 1, media, rng) * weight
+                }
+                _ => Color::black(),
+            };
+            diffuse * (1.0 - metallic)
+                + glossy_color(scene, &lobe, hit_point, side, view_direction, rng)
+                + reflection
+        }
     }
 }
 
-fn diffuse_color(
+//What a ray that hits nothing sees.
+pub fn miss_color(scene: &Scene, ray: &Ray, camera_ray: bool) -> Color {
+    match (scene.background.as_ref(), scene.environment.as_ref()) {
+        (Some(background), _) if camera_ray || !scene.hide_background_in_reflections => {
+            background.color(&ray.direction, &scene.camera, sensor_size(scene))
+        }
+        (_, Some(environment)) => environment.color(&ray.direction),
+        _ => Color::black(),
+    }
+}
+
+//The material's colour where the ray hit, with textures filtered over the pixel's footprint.
+pub fn surface_color(
     scene: &Scene,
-    element: &Element,
+    ray: &Ray,
+    intersection: &Intersection,
     hit_point: Point,
     surface_normal: Vector3,
 ) -> Color {
-    let mut color = Color::black();
-    for light in &scene.lights {
-        let direction_to_light = light.direction_from(&hit_point);
-        let shadow_ray = Ray {
-            origin: hit_point + (direction_to_light * scene.shadow_bias),
-            direction: direction_to_light,
-        };
-        let shadow_intersection = scene.trace(&shadow_ray);
-        let in_light = shadow_intersection.is_none()
-            || shadow_intersection.unwrap().distance > light.distance(&hit_point);
-        let light_intensity = if in_light {
-            light.intensity(&hit_point)
+    let surface = intersection.surface;
+    let texture_coords = surface.texture_coords(&hit_point);
+    let footprint = texture_footprint(
+        scene,
+        surface,
+        &texture_coords,
+        hit_point,
+        surface_normal,
+        ray,
+        intersection.distance,
+    );
+    intersection
+        .element
+        .material()
+        .coloration
+        .color(&texture_coords, &hit_point, footprint)
+}
+
+//How far across the texture the spread of one pixel reaches at the hit point. Every ray is
+//treated as if it had come straight from the camera, so reflections and refractions filter
+//less than they might.
+fn texture_footprint(
+    scene: &Scene,
+    surface: &dyn Intersectable,
+    texture_coords: &TextureCoords,
+    hit_point: Point,
+    surface_normal: Vector3,
+    ray: &Ray,
+    distance: f64,
+) -> f32 {
+    let width = distance * 2.0 * sensor_size(scene).1 / scene.height as f64;
+    let cos = surface_normal.dot(&ray.direction);
+    //The pixel stretches out along the ray's path over the surface as the surface tilts away.
+    let along = ray.direction - surface_normal * cos;
+    let (along, across) = if along.length() > 1e-6 {
+        let along = along.normalise();
+        (along, surface_normal.cross(&along))
+    } else {
+        orthonormal_basis(&surface_normal)
+    };
+    let step = |offset: Vector3| {
+        let coords = surface.texture_coords(&(hit_point + offset));
+        let (du, dv) = (coords.x - texture_coords.x, coords.y - texture_coords.y);
+        (du * du + dv * dv).sqrt()
+    };
+    //Stepping both ways and keeping the shorter ignores the jump where a mapping wraps around.
+    let reach = |offset: Vector3| step(offset).min(step(-offset));
+    let slant = cos.abs().max(0.05);
+    reach(along * (width / slant)).max(reach(across * width))
+}
+
+pub fn diffuse_color<R: Rng>(
+    scene: &Scene,
+    material: &Material,
+    surface_color: Color,
+    hit_point: Point,
+    surface_normal: Vector3,
+    side: Vector3,
+    rng: &mut R,
+) -> Color {
+    let irradiance = gather_light(scene, hit_point, side, true, rng, |direction| {
+        Color::white() * surface_normal.dot(direction) as f32
+    });
+    let light_reflected = material.albedo / PI;
+    surface_color * irradiance * light_reflected
+}
+
+//Blinn-Phong highlights seen from `view_direction`, which points away from the surface.
+pub fn specular_color<R: Rng>(
+    scene: &Scene,
+    material: &Material,
+    hit_point: Point,
+    surface_normal: Vector3,
+    side: Vector3,
+    view_direction: Vector3,
+    rng: &mut R,
+) -> Color {
+    if material.specular.max_component() <= 0.0 {
+        return Color::black();
+    }
+    let shininess = material.shininess.max(0.0);
+    let light = gather_light(scene, hit_point, side, true, rng, |direction| {
+        let cos_theta = surface_normal.dot(direction) as f32;
+        let half_vector = (*direction + view_direction).normalise();
+        Color::white()
+            * ((surface_normal.dot(&half_vector).max(0.0) as f32).powf(shininess) * cos_theta)
+    });
+    //Keeps the total reflected light roughly constant as the highlight narrows.
+    let normalisation = (shininess + 8.0) / (8.0 * PI);
+    material.specular * light * normalisation
+}
+
+//Highlights of the lights in a glossy lobe. The environment and emissive elements are left to
+//the reflected rays, which find them according to the lobe.
+pub fn glossy_color<R: Rng>(
+    scene: &Scene,
+    lobe: &Ggx,
+    hit_point: Point,
+    side: Vector3,
+    view_direction: Vector3,
+    rng: &mut R,
+) -> Color {
+    gather_light(scene, hit_point, side, false, rng, |direction| {
+        lobe.evaluate(&view_direction, direction)
+    })
+}
+
+//Light from every light source, with each sample weighted by how the surface responds to light
+//from its direction. Only light arriving on `side` of the geometric surface counts, whatever the
+//shading normal says. The environment and emissive elements, which rays can also run into, are
+//only included with `reachable_sources`.
+fn gather_light<R, W>(
+    scene: &Scene,
+    hit_point: Point,
+    side: Vector3,
+    reachable_sources: bool,
+    rng: &mut R,
+    response: W,
+) -> Color
+where
+    R: Rng,
+    W: Fn(&Vector3) -> Color,
+{
+    let response = |direction: &Vector3| {
+        if direction.dot(&side) > 0.0 {
+            response(direction)
         } else {
-            0.0
+            Color::black()
+        }
+    };
+    let mut light = Color::black();
+    for source in scene.light_sources() {
+        light = light + sample_light(scene, hit_point, source.samples(), rng, &response, |rng| {
+            source.sample(&hit_point, rng)
+        });
+    }
+    if !reachable_sources {
+        return light;
+    }
+    for emitter in &scene.emitters {
+        let element = &scene.elements[emitter.element];
+        let n_samples = element.material().emission.as_ref().map_or(1, |e| e.samples);
+        light = light + sample_light(scene, hit_point, n_samples, rng, &response, |rng| {
+            emitter.sample(element, &hit_point, rng)
+        });
+    }
+    if let Some(ref environment) = scene.environment {
+        light = light + sample_light(scene, hit_point, environment.samples, rng, &response, |rng| {
+            environment.sample(rng)
+        });
+    }
+    light
+}
+
+//Average over `n_samples` shadow tested samples of one light.
+fn sample_light<R, W, F>(
+    scene: &Scene,
+    hit_point: Point,
+    n_samples: u32,
+    rng: &mut R,
+    response: &W,
+    mut sample_light: F,
+) -> Color
+where
+    R: Rng,
+    W: Fn(&Vector3) -> Color,
+    F: FnMut(&mut R) -> Option<LightSample>,
+{
+    let n_samples = n_samples.max(1);
+    let mut light = Color::black();
+    for _ in 0..n_samples {
+        let sample = match sample_light(rng) {
+            Some(sample) => sample,
+            None => continue,
         };
-        let light_power =
-            (surface_normal.dot(&direction_to_light) as f32).max(0.0) * light_intensity;
-        let light_reflected = element.albedo() / PI;
-        let light_color = light.color() * light_power * light_reflected;
-        color = color + (element.color(&hit_point) * light_color);
+        let weight = response(&sample.direction);
+        if weight.max_component() <= 0.0 {
+            continue;
+        }
+        let shadow_ray = Ray {
+            origin: hit_point + (sample.direction * scene.shadow_bias),
+            direction: sample.direction,
+        };
+        if scene.occluded(&shadow_ray, sample.distance) {
+            continue;
+        }
+        light = light + sample.color * weight * sample.intensity;
+    }
+    light * (1.0 / n_samples as f32)
+}
+
+//Beer-Lambert: the fraction of light left after `distance` through an absorbing medium.
+pub fn transmittance(absorption: Color, distance: f64) -> Color {
+    let distance = distance as f32;
+    Color {
+        red: (-absorption.red * distance).exp(),
+        green: (-absorption.green * distance).exp(),
+        blue: (-absorption.blue * distance).exp(),
+    }
+}
+
+//A microfacet normal of a frosted surface to refract and reflect about, in place of the
+//surface normal and facing the same way.
+pub fn frosted_normal<R: Rng>(
+    normal: Vector3,
+    incident: Vector3,
+    roughness: f32,
+    rng: &mut R,
+) -> Vector3 {
+    if roughness <= 0.0 {
+        return normal;
+    }
+    let entering = normal.dot(&incident) < 0.0;
+    let facing = if entering { normal } else { -normal };
+    let microfacet = sample_normal(&facing, roughness_to_alpha(roughness), rng);
+    if microfacet.dot(&incident) >= 0.0 {
+        //Seen from behind; keep to the smooth surface rather than lose the sample.
+        normal
+    } else if entering {
+        microfacet
+    } else {
+        -microfacet
     }
-    color.clamp()
 }
 
-fn fresnel(incident: Vector3, normal: Vector3, index: f32) -> f64 {
+pub fn fresnel(incident: Vector3, normal: Vector3, index: f32) -> f64 {
     let mut eta_t = index as f64;
     let mut eta_i = 1.0f64;
     let i_dot_n = incident.dot(&normal);
@@ -359,3 +839,234 @@ fn fresnel(incident: Vector3, normal: Vector3, index: f32) -> f64 {
         return (r_s * r_s + r_p * r_p) / 2.0;
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::rngs::StdRng;
+    use rand::SeedableRng;
+    use serde_json;
+
+    fn in_plane(degrees: f64) -> Vector3 {
+        let (sin, cos) = degrees.to_radians().sin_cos();
+        Vector3 {
+            x: sin,
+            y: cos,
+            z: 0.0,
+        }
+    }
+
+    fn camera_scene(aperture: f64, focus_distance: Option<f64>) -> Scene {
+        let mut scene = Scene::with("[]", "[]");
+        scene.camera.position = Point {
+            x: 1.0,
+            y: 2.0,
+            z: 3.0,
+        };
+        scene.camera.look_at = Point {
+            x: 4.0,
+            y: 0.0,
+            z: -1.0,
+        };
+        scene.camera.aperture = aperture;
+        scene.camera.focus_distance = focus_distance;
+        scene.prepare();
+        scene
+    }
+
+    //Every ray through the lens for one pixel should cross the plane `depth` in front of the
+    //camera where the ray through the centre of the lens does.
+    fn check_focus(scene: &Scene, depth: f64) {
+        let camera = &scene.camera;
+        let forward = (camera.look_at - camera.position).normalise();
+        let mut rng = StdRng::seed_from_u64(0);
+        let centre = Ray::create_prime(5.0, 7.0, &camera_scene(0.0, None), &mut rng);
+        let focus = centre.origin + centre.direction * (depth / centre.direction.dot(&forward));
+        for _ in 0..16 {
+            let ray = Ray::create_prime(5.0, 7.0, scene, &mut rng);
+            let offset = ray.origin - camera.position;
+            assert!(offset.length() <= camera.aperture * 0.5 + 1e-9);
+            assert!(offset.dot(&forward).abs() < 1e-9);
+            let along = (depth - offset.dot(&forward)) / ray.direction.dot(&forward);
+            assert!(((ray.origin + ray.direction * along) - focus).length() < 1e-9);
+        }
+    }
+
+    #[test]
+    fn test_lens_rays_meet_at_the_focus_distance() {
+        check_focus(&camera_scene(0.5, Some(6.0)), 6.0);
+    }
+
+    #[test]
+    fn test_focus_distance_defaults_to_the_look_at_point() {
+        let scene = camera_scene(0.5, None);
+        let depth = (scene.camera.look_at - scene.camera.position).length();
+        assert_eq!(scene.camera.focus_distance(), depth);
+        check_focus(&scene, depth);
+    }
+
+    #[test]
+    fn test_zero_aperture_is_a_pinhole() {
+        let scene = camera_scene(0.0, Some(6.0));
+        let (half_width, half_height) = sensor_size(&scene);
+        let sensor = Vector3 {
+            x: ((5.0 + 0.5) / 32.0 * 2.0 - 1.0) * half_width,
+            y: (1.0 - (7.0 + 0.5) / 32.0 * 2.0) * half_height,
+            z: -1.0,
+        };
+        let expected = (scene.camera.rotation_matrix * sensor).normalise();
+        let ray = Ray::create_prime(5.0, 7.0, &scene, &mut StdRng::seed_from_u64(0));
+        let origin = scene.camera.position;
+        assert_eq!((ray.origin.x, ray.origin.y, ray.origin.z), (origin.x, origin.y, origin.z));
+        assert_eq!(
+            (ray.direction.x, ray.direction.y, ray.direction.z),
+            (expected.x, expected.y, expected.z)
+        );
+    }
+
+    #[test]
+    fn test_blinn_phong_highlight_peaks_at_the_mirror_direction() {
+        //A light 30 degrees off the normal, on one side of it.
+        let scene = Scene::with(
+            "[]",
+            r#"[{"Directional": {"direction": {"x": -0.5, "y": -0.8660254037844386, "z": 0.0},
+                "color": {"red": 1.0, "green": 1.0, "blue": 1.0}, "intensity": 2.0}}]"#,
+        );
+        let material: Material = serde_json::from_str(
+            r#"{"coloration": {"Color": {"red": 1.0, "green": 1.0, "blue": 1.0}},
+                "albedo": 0.0, "surface": "Diffuse",
+                "specular": {"red": 1.0, "green": 1.0, "blue": 1.0}, "shininess": 64.0}"#,
+        )
+        .unwrap();
+        let mut rng = StdRng::seed_from_u64(0);
+        let mut highlight = |view: f64| {
+            specular_color(
+                &scene,
+                &material,
+                Point::zero(),
+                in_plane(0.0),
+                in_plane(0.0),
+                in_plane(view),
+                &mut rng,
+            )
+            .red as f64
+        };
+        let normalisation = (64.0 + 8.0) / (8.0 * ::std::f64::consts::PI);
+        let peak = normalisation * 2.0 * 30f64.to_radians().cos();
+        assert!((highlight(-30.0) - peak).abs() < 1e-4 * peak);
+        //Looking 10 degrees away tilts the half vector by 5.
+        let off_peak = peak * 5f64.to_radians().cos().powf(64.0);
+        assert!((highlight(-20.0) - off_peak).abs() < 1e-4 * peak);
+        assert!((highlight(-40.0) - off_peak).abs() < 1e-4 * peak);
+        assert!(highlight(30.0) < 0.01 * peak);
+    }
+
+    #[test]
+    fn test_transmittance_follows_beer_lambert() {
+        let absorption = Color {
+            red: 0.0,
+            green: 1.0,
+            blue: 2.0,
+        };
+        let through = transmittance(absorption, 0.5);
+        assert_eq!(through.red, 1.0);
+        assert!((through.green - (-0.5f32).exp()).abs() < 1e-6);
+        assert!((through.blue - (-1.0f32).exp()).abs() < 1e-6);
+        //Two stretches absorb as much as one of their combined length.
+        let split = transmittance(absorption, 0.2) * transmittance(absorption, 0.3);
+        assert!((split.blue - through.blue).abs() < 1e-6);
+    }
+
+    #[test]
+    fn test_frosted_normals_face_the_surface_normal() {
+        let normal = in_plane(0.0);
+        let entering = in_plane(150.0);
+        let leaving = in_plane(30.0);
+        let mut rng = StdRng::seed_from_u64(0);
+        let smooth = frosted_normal(normal, entering, 0.0, &mut rng);
+        assert_eq!((smooth.x, smooth.y, smooth.z), (normal.x, normal.y, normal.z));
+        let mut spread = |roughness: f32| {
+            let mut total = 0.0;
+            for i in 0..1000 {
+                let incident = if i % 2 == 0 { entering } else { leaving };
+                let frosted = frosted_normal(normal, incident, roughness, &mut rng);
+                assert!((frosted.length() - 1.0).abs() < 1e-9);
+                assert!(frosted.dot(&normal) > 0.0);
+                //Always seen from the side the ray arrives on.
+                assert!(frosted.dot(&incident) * normal.dot(&incident) > 0.0);
+                total += frosted.dot(&normal);
+            }
+            total / 1000.0
+        };
+        let (light, heavy) = (spread(0.1), spread(0.6));
+        assert!(light > 0.99);
+        assert!(heavy < light);
+    }
+
+    //Stepping `step` along dp/du should move u, and only u, on by `step`; likewise for v.
+    fn check_tangent_frame(surface: &dyn Intersectable, hit_point: Point, step: f64, tolerance: f32) {
+        let (dp_du, dp_dv) = surface.tangent_frame(&hit_point);
+        let coords = surface.texture_coords(&hit_point);
+        let along_u = surface.texture_coords(&(hit_point + dp_du * step));
+        let along_v = surface.texture_coords(&(hit_point + dp_dv * step));
+        let step = step as f32;
+        assert!((along_u.x - coords.x - step).abs() < tolerance);
+        assert!((along_u.y - coords.y).abs() < tolerance);
+        assert!((along_v.x - coords.x).abs() < tolerance);
+        assert!((along_v.y - coords.y - step).abs() < tolerance);
+    }
+
+    #[test]
+    fn test_sphere_tangent_frame_follows_texture_coords() {
+        let sphere = Sphere {
+            centre: Point {
+                x: 1.0,
+                y: 2.0,
+                z: -3.0,
+            },
+            radius: 2.0,
+            ..Default::default()
+        };
+        //Away from the seam, where u wraps round from 1 to 0.
+        let directions = [
+            in_plane(60.0),
+            Vector3 {
+                x: 0.3,
+                y: -0.4,
+                z: 0.8,
+            },
+            Vector3 {
+                x: -0.5,
+                y: 0.5,
+                z: -0.7,
+            },
+        ];
+        for direction in &directions {
+            let hit_point = sphere.centre + direction.normalise() * sphere.radius;
+            //The sphere curves away from the tangent plane, so only to first order.
+            check_tangent_frame(&sphere, hit_point, 1e-3, 2e-5);
+        }
+    }
+
+    #[test]
+    fn test_plane_tangent_frame_follows_texture_coords() {
+        let tilted = Vector3 {
+            x: 0.3,
+            y: -0.8,
+            z: 0.5,
+        };
+        for normal in &[in_plane(180.0), tilted.normalise()] {
+            let plane = Plane {
+                origin: Point {
+                    x: 0.5,
+                    y: -1.0,
+                    z: 2.0,
+                },
+                normal: *normal,
+                ..Default::default()
+            };
+            let hit_point = plane.origin + plane_axes(normal).0 * 0.7;
+            check_tangent_frame(&plane, hit_point, 0.25, 1e-6);
+        }
+    }
+}
@@ -0,0 +1,149 @@
+use point::Point;
+use rand::Rng;
+use std::f64::consts::PI;
+use vector::Vector3;
+
+// Shirley and Chiu's concentric mapping of the unit square to the unit disk, which keeps
+// stratified samples well spread.
+pub fn concentric_disk<R: Rng>(rng: &mut R) -> (f64, f64) {
+    let u = 2.0 * rng.gen::<f64>() - 1.0;
+    let v = 2.0 * rng.gen::<f64>() - 1.0;
+    if u == 0.0 && v == 0.0 {
+        return (0.0, 0.0);
+    }
+    let (r, theta) = if u.abs() > v.abs() {
+        (u, (PI / 4.0) * (v / u))
+    } else {
+        (v, (PI / 2.0) - (PI / 4.0) * (u / v))
+    };
+    (r * theta.cos(), r * theta.sin())
+}
+
+// Two unit vectors perpendicular to the (unit) normal and to each other (Duff et al. 2017).
+pub fn orthonormal_basis(normal: &Vector3) -> (Vector3, Vector3) {
+    let sign = 1.0f64.copysign(normal.z);
+    let a = -1.0 / (sign + normal.z);
+    let b = normal.x * normal.y * a;
+    (
+        Vector3 {
+            x: 1.0 + sign * normal.x * normal.x * a,
+            y: sign * b,
+            z: -sign * normal.x,
+        },
+        Vector3 {
+            x: b,
+            y: sign + normal.y * normal.y * a,
+            z: -normal.y,
+        },
+    )
+}
+
+// Direction in the hemisphere around the normal, with density cos(theta) / pi.
+pub fn cosine_hemisphere<R: Rng>(normal: &Vector3, rng: &mut R) -> Vector3 {
+    let (x, y) = concentric_disk(rng);
+    let z = (1.0 - x * x - y * y).max(0.0).sqrt();
+    let (tangent, bitangent) = orthonormal_basis(normal);
+    (tangent * x + bitangent * y + *normal * z).normalise()
+}
+
+// Direction within `cos_max` of the axis, uniform over the solid angle of the cone.
+pub fn uniform_cone<R: Rng>(axis: &Vector3, cos_max: f64, rng: &mut R) -> Vector3 {
+    let cos_theta = 1.0 - rng.gen::<f64>() * (1.0 - cos_max);
+    let sin_theta = (1.0 - cos_theta * cos_theta).max(0.0).sqrt();
+    let phi = 2.0 * PI * rng.gen::<f64>();
+    let (tangent, bitangent) = orthonormal_basis(axis);
+    (tangent * (sin_theta * phi.cos()) + bitangent * (sin_theta * phi.sin()) + *axis * cos_theta)
+        .normalise()
+}
+
+// Direction from `from` towards a sphere, uniform over the cone the sphere subtends, with the
+// distance to its near surface and the cone's solid angle. None from inside the sphere.
+pub fn sphere_cone<R: Rng>(
+    centre: &Point,
+    radius: f64,
+    from: &Point,
+    rng: &mut R,
+) -> Option<(Vector3, f64, f64)> {
+    let to_centre = *centre - *from;
+    let centre_distance = to_centre.length();
+    if centre_distance <= radius {
+        return None;
+    }
+    let axis = to_centre * (1.0 / centre_distance);
+    let sin_max = radius / centre_distance;
+    let cos_max = (1.0 - sin_max * sin_max).max(0.0).sqrt();
+    let direction = uniform_cone(&axis, cos_max, rng);
+    let cos_theta = direction.dot(&axis);
+    let sin2_theta = (1.0 - cos_theta * cos_theta).max(0.0);
+    let distance = centre_distance * cos_theta
+        - (radius * radius - centre_distance * centre_distance * sin2_theta)
+            .max(0.0)
+            .sqrt();
+    Some((direction, distance, 2.0 * PI * (1.0 - cos_max)))
+}
+
+// Piecewise constant distribution over `weights.len()` bins, sampled by inverting its CDF.
+pub struct Distribution1D {
+    // Normalised, so the last entry is 1.
+    cdf: Vec<f64>,
+    total: f64,
+}
+
+impl Distribution1D {
+    pub fn new(weights: &[f64]) -> Distribution1D {
+        let mut cdf = Vec::with_capacity(weights.len() + 1);
+        let mut total = 0.0;
+        cdf.push(0.0);
+        for weight in weights {
+            total += weight.max(0.0);
+            cdf.push(total);
+        }
+        // With nothing to go on every bin is equally likely, though the total stays 0.
+        let n = weights.len() as f64;
+        for (i, c) in cdf.iter_mut().enumerate() {
+            *c = if total > 0.0 { *c / total } else { i as f64 / n };
+        }
+        Distribution1D { cdf, total }
+    }
+
+    // Sum of the weights.
+    pub fn total(&self) -> f64 {
+        self.total
+    }
+
+    // Bin containing `u` in [0, 1), along with its probability.
+    pub fn sample(&self, u: f64) -> (usize, f64) {
+        let index = self.cdf[1..]
+            .partition_point(|&c| c <= u)
+            .min(self.cdf.len() - 2);
+        (index, self.probability(index))
+    }
+
+    pub fn probability(&self, index: usize) -> f64 {
+        self.cdf[index + 1] - self.cdf[index]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_distribution_sampling_follows_weights() {
+        let distribution = Distribution1D::new(&[1.0, 0.0, 3.0]);
+        assert_eq!(distribution.sample(0.1), (0, 0.25));
+        assert_eq!(distribution.sample(0.3).0, 2);
+        assert_eq!(distribution.sample(0.999).0, 2);
+        assert_eq!(distribution.probability(1), 0.0);
+    }
+
+    #[test]
+    fn test_distribution_without_weight_is_uniform_with_zero_total() {
+        let distribution = Distribution1D::new(&[0.0, 0.0, 0.0, 0.0]);
+        assert_eq!(distribution.total(), 0.0);
+        assert_eq!(distribution.sample(0.6), (2, 0.25));
+        // So a black row of an environment map is never picked over a lit one.
+        let rows = Distribution1D::new(&[distribution.total(), 2.0]);
+        assert_eq!(rows.sample(0.0).0, 1);
+    }
+}
@@ -1,82 +1,147 @@
-use image;
-use image::{DynamicImage, GenericImageView, Pixel, Rgba};
+use bump::{BumpMap, NormalMap};
+use bvh::{Aabb, Bounded, Bvh};
+use environment::{Background, Environment};
+use emission::{Emission, Emitter};
+use error::{deserialize_elements, deserialize_lights, reset_current_item, SceneError};
+use image::{Pixel, Rgba};
 use matrix::Matrix33;
+use mesh::{load_obj, write_obj_path, Face, ObjModel};
 use point::Point;
+use procedural::Procedural;
 use rendering::{Intersectable, Ray, TextureCoords};
 use serde;
-use serde::{Deserialize, Serializer, Deserializer};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use serde_json;
+use serde_yaml;
+use std::collections::BTreeMap;
+use std::ffi::OsStr;
+use std::fs::File;
+use std::io;
+use std::io::{BufReader, BufWriter};
 use std::ops::{Add, Mul};
-use std::path::PathBuf;
+use std::path::Path;
+use tonemap::{srgb_encode, ToneMapper};
 use vector::Vector3;
 use rand;
-
-const GAMMA: f32 = 2.2;
-
-fn gamma_encode(linear: f32) -> f32 {
-    linear.powf(1.0 / GAMMA)
-}
-
-fn gamma_decode(encoded: f32) -> f32 {
-    encoded.powf(GAMMA)
-}
+use rand::Rng;
+use sampling::{concentric_disk, orthonormal_basis, sphere_cone};
+use spectrum::Dispersion;
+use texture::{Texture, TextureCache, TextureSource};
+use std::f64::consts::PI;
 
 #[derive(Serialize, Deserialize, Clone)]
 pub struct Material {
     pub coloration: Coloration,
     pub albedo: f32,
     pub surface: SurfaceType,
+    //Blinn-Phong highlight from lights; black for none.
+    #[serde(default = "Color::black")]
+    pub specular: Color,
+    #[serde(default = "default_shininess")]
+    pub shininess: f32,
+    #[serde(default)]
+    pub emission: Option<Emission>,
+    #[serde(default)]
+    pub normal_map: Option<NormalMap>,
+    #[serde(default)]
+    pub bump_map: Option<BumpMap>,
+}
+
+impl Material {
+    pub fn emitted(&self) -> Color {
+        self.emission
+            .as_ref()
+            .map(|e| e.radiance())
+            .unwrap_or(Color::black())
+    }
+}
+
+fn default_shininess() -> f32 {
+    32.0
 }
 
 #[derive(Serialize, Deserialize, Clone, Debug)]
 pub enum SurfaceType {
     Diffuse,
     Reflective { reflectivity: f32 },
-    Refractive { index: f32, transparency: f32 },
+    Refractive {
+        index: f32,
+        transparency: f32,
+        //Fraction of each channel absorbed per unit distance travelled inside.
+        #[serde(default = "Color::black")]
+        absorption: Color,
+        //Frosts the surface by scattering refractions like a GGX microfacet surface.
+        #[serde(default)]
+        roughness: f32,
+        //Decides which of two overlapping dielectrics fills the overlap; higher wins.
+        #[serde(default)]
+        priority: u32,
+        //Varies `index` with wavelength when the scene is rendered spectrally.
+        #[serde(default)]
+        dispersion: Option<Dispersion>,
+    },
+    //Physically based: a GGX glossy lobe over a Lambertian base, which metals lack.
+    Microfacet {
+        roughness: f32,
+        #[serde(default)]
+        metallic: f32,
+        #[serde(default = "default_ior")]
+        ior: f32,
+    },
+}
+
+fn default_ior() -> f32 {
+    1.5
 }
 
 #[derive(Serialize, Deserialize, Clone)]
 pub enum Coloration {
     Color(Color),
-    Texture(#[serde(deserialize_with = "load_texture", serialize_with = "write_string")] DynamicImage),
+    Texture(#[serde(deserialize_with = "load_texture", serialize_with = "write_texture")] Texture),
+    #[serde(deserialize_with = "Procedural::deserialize_checked")]
+    Procedural(Procedural),
 }
 
 impl Coloration {
-    pub fn color(&self, texture_coords: &TextureCoords) -> Color {
+    //`position` is the point being shaded, for patterns laid out in world space, and `footprint`
+    //the width in texture coordinates of the area it stands for, which filtered textures average.
+    pub fn color(&self, texture_coords: &TextureCoords, position: &Point, footprint: f32) -> Color {
         match *self {
             Coloration::Color(c) => c,
-            Coloration::Texture(ref tex) => {
-                let tex_x = wrap(texture_coords.x, tex.width());
-                let tex_y = wrap(texture_coords.y, tex.height());
-                Color::from_rgba(tex.get_pixel(tex_x, tex_y))
-            }
+            Coloration::Procedural(ref p) => p.color(texture_coords, position),
+            Coloration::Texture(ref tex) => tex.color(texture_coords, footprint),
         }
     }
 }
 
-pub fn load_texture<'de, D>(deserializer: D) -> Result<DynamicImage, D::Error>
+pub fn load_texture<'de, D>(deserializer: D) -> Result<Texture, D::Error>
 where
     D: Deserializer<'de>,
 {
-    let path = PathBuf::deserialize(deserializer)?;
-    Ok(image::open(path).expect("Unable to open texture file"))
+    TextureSource::deserialize(deserializer).map(TextureSource::into_texture)
 }
 
-pub fn write_string<S>(_: &DynamicImage, serializer: S) -> Result<S::Ok, S::Error>
+//Textures taken from the scene's named textures are written back as just the name, which is all
+//they could have been given as.
+pub fn write_texture<S>(texture: &Texture, serializer: S) -> Result<S::Ok, S::Error>
 where
     S: Serializer,
 {
-    serializer.serialize_str("texture file")
+    match texture.name {
+        Some(ref name) => serializer.serialize_str(name),
+        None => texture.serialize(serializer),
+    }
 }
 
-fn wrap(val: f32, bound: u32) -> u32 {
-    let signed_bound = bound as i32;
-    let float_coord = val * bound as f32;
-    let wrapped_coord = (float_coord as i32) % signed_bound;
-    if wrapped_coord < 0 {
-        (wrapped_coord + signed_bound) as u32
-    } else {
-        wrapped_coord as u32
-    }
+pub fn load_textures<'de, D>(deserializer: D) -> Result<BTreeMap<String, Texture>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let sources = BTreeMap::<String, TextureSource>::deserialize(deserializer)?;
+    Ok(sources
+        .into_iter()
+        .map(|(name, source)| (name, source.into_texture()))
+        .collect())
 }
 
 #[derive(Serialize, Deserialize, Clone, Copy, Debug)]
@@ -104,20 +169,21 @@ impl Color {
     }
 
     pub fn to_rgba(&self) -> Rgba<u8> {
+        let clamped = self.clamp();
         Rgba::from_channels(
-            (gamma_encode(self.red) * 255.0) as u8,
-            (gamma_encode(self.green) * 255.0) as u8,
-            (gamma_encode(self.blue) * 255.0) as u8,
+            (srgb_encode(clamped.red) * 255.0) as u8,
+            (srgb_encode(clamped.green) * 255.0) as u8,
+            (srgb_encode(clamped.blue) * 255.0) as u8,
             255,
         )
     }
 
-    pub fn from_rgba(rgba: Rgba<u8>) -> Color {
-        Color {
-            red: gamma_decode((rgba[0] as f32) / 255.0),
-            green: gamma_decode((rgba[1] as f32) / 255.0),
-            blue: gamma_decode((rgba[2] as f32) / 255.0),
-        }
+    pub fn luminance(&self) -> f32 {
+        0.2126 * self.red + 0.7152 * self.green + 0.0722 * self.blue
+    }
+
+    pub fn max_component(&self) -> f32 {
+        self.red.max(self.green).max(self.blue)
     }
 
     pub fn clamp(&self) -> Color {
@@ -178,6 +244,8 @@ pub enum Element {
     Sphere(Sphere),
     Plane(Plane),
     Disk(Disk),
+    Triangle(Triangle),
+    Mesh(Mesh),
 }
 
 #[derive(Serialize, Deserialize)]
@@ -204,6 +272,11 @@ impl Default for Sphere {
                     }),
                     albedo: rand::random::<f32>(),
                     surface: SurfaceType::Diffuse,
+                    specular: Color::black(),
+                    shininess: default_shininess(),
+                    emission: None,
+                    normal_map: None,
+                    bump_map: None,
                 },
         }
     }
@@ -238,6 +311,11 @@ impl Default for Plane {
                 }),
                 albedo: 0.23,
                 surface: SurfaceType::Reflective { reflectivity: 0.3 },
+                specular: Color::black(),
+                shininess: default_shininess(),
+                emission: None,
+                normal_map: None,
+                bump_map: None,
             },
         }
     }
@@ -252,20 +330,28 @@ pub struct Disk {
     pub material: Material,
 }
 
-impl Element {
-    pub fn color(&self, hit: &Point) -> Color {
-        match *self {
-            Element::Sphere(ref s) => s.material.coloration.color(&self.texture_coords(hit)),
-            Element::Plane(ref p) => p.material.coloration.color(&self.texture_coords(hit)),
-            Element::Disk(ref d) => d.material.coloration.color(&self.texture_coords(hit)),
-        }
-    }
+#[derive(Serialize, Deserialize)]
+pub struct Triangle {
+    #[serde(flatten)]
+    pub face: Face,
+    pub material: Material,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct Mesh {
+    #[serde(rename = "path", deserialize_with = "load_obj", serialize_with = "write_obj_path")]
+    pub model: ObjModel,
+    pub material: Material,
+}
 
-    pub fn albedo(&self) -> &f32 {
+impl Element {
+    pub fn bounds(&self) -> Option<Aabb> {
         match *self {
-            Element::Sphere(ref s) => &s.material.albedo,
-            Element::Plane(ref p) => &p.material.albedo,
-            Element::Disk(ref d) => &d.material.albedo,
+            Element::Sphere(ref s) => Some(s.bounds()),
+            Element::Plane(_) => None,
+            Element::Disk(ref d) => Some(d.bounds()),
+            Element::Triangle(ref t) => Some(t.bounds()),
+            Element::Mesh(ref m) => Some(m.bounds()),
         }
     }
 
@@ -274,6 +360,18 @@ impl Element {
             Element::Sphere(ref s) => &s.material,
             Element::Plane(ref p) => &p.material,
             Element::Disk(ref d) => &d.material,
+            Element::Triangle(ref t) => &t.material,
+            Element::Mesh(ref m) => &m.material,
+        }
+    }
+
+    pub fn material_mut(&mut self) -> &mut Material {
+        match *self {
+            Element::Sphere(ref mut s) => &mut s.material,
+            Element::Plane(ref mut p) => &mut p.material,
+            Element::Disk(ref mut d) => &mut d.material,
+            Element::Triangle(ref mut t) => &mut t.material,
+            Element::Mesh(ref mut m) => &mut m.material,
         }
     }
 }
@@ -300,11 +398,20 @@ pub struct Camera {
     pub look_at: Point,
     #[serde(default = "Vector3::default_up")]
     pub up: Vector3,
+    #[serde(default)]
+    pub aperture: f64,
+    #[serde(default)]
+    pub focus_distance: Option<f64>,
     #[serde(skip_deserializing, skip_serializing)]
     pub rotation_matrix: Matrix33,
 }
 
 impl Camera {
+    pub fn focus_distance(&self) -> f64 {
+        self.focus_distance
+            .unwrap_or_else(|| (self.look_at - self.position).length())
+    }
+
     pub fn calculate_rotation_matrix(
         look_at: Point,
         position: Point,
@@ -317,10 +424,141 @@ impl Camera {
     }
 }
 
+#[derive(Serialize, Deserialize)]
+pub struct RectangleLight {
+    //One corner; the light spans corner + u * [0, 1] + v * [0, 1] and shines towards u x v.
+    pub position: Point,
+    pub u: Vector3,
+    pub v: Vector3,
+    pub color: Color,
+    pub intensity: f32,
+    #[serde(default = "default_light_samples")]
+    pub samples: u32,
+}
+
+impl RectangleLight {
+    //The light's normal is u x v, which is undefined if the edges are parallel or either is zero.
+    fn deserialize_checked<'de, D>(deserializer: D) -> Result<RectangleLight, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        use serde::de::Error;
+        let light = RectangleLight::deserialize(deserializer)?;
+        if light.u.cross(&light.v).length() < 1e-12 {
+            return Err(D::Error::custom(
+                "a rectangle light's u and v must be non-zero and not parallel",
+            ));
+        }
+        Ok(light)
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct DiskLight {
+    pub position: Point,
+    #[serde(deserialize_with = "Vector3::deserialize_normalized")]
+    pub normal: Vector3,
+    pub radius: f64,
+    pub color: Color,
+    pub intensity: f32,
+    #[serde(default = "default_light_samples")]
+    pub samples: u32,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct SphereLight {
+    pub position: Point,
+    pub radius: f64,
+    pub color: Color,
+    pub intensity: f32,
+    #[serde(default = "default_light_samples")]
+    pub samples: u32,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct SpotLight {
+    pub position: Point,
+    #[serde(deserialize_with = "Vector3::deserialize_normalized")]
+    pub direction: Vector3,
+    //Half angles in degrees, measured from `direction`. Full intensity inside the inner cone,
+    //fading smoothly to nothing at the outer one.
+    pub inner_angle: f64,
+    pub outer_angle: f64,
+    pub color: Color,
+    pub intensity: f32,
+    //Projected through the cone so that the image exactly fills the outer angle.
+    #[serde(default)]
+    pub gobo: Option<Coloration>,
+}
+
+impl SpotLight {
+    fn falloff(&self, direction_to_light: &Vector3) -> f32 {
+        let cos_angle = -direction_to_light.dot(&self.direction);
+        let cos_outer = self.outer_angle.to_radians().cos();
+        let cos_inner = self.inner_angle.min(self.outer_angle).to_radians().cos();
+        if cos_angle <= cos_outer {
+            return 0.0;
+        }
+        if cos_angle >= cos_inner {
+            return 1.0;
+        }
+        let t = (cos_angle - cos_outer) / (cos_inner - cos_outer);
+        (t * t * (3.0 - 2.0 * t)) as f32
+    }
+
+    fn gobo_color(&self, direction_to_light: &Vector3) -> Color {
+        let gobo = match self.gobo {
+            Some(ref gobo) => gobo,
+            None => return Color::white(),
+        };
+        let (right, up) = orthonormal_basis(&self.direction);
+        let outward = -*direction_to_light;
+        let forward = outward.dot(&self.direction);
+        if forward <= 0.0 {
+            return Color::black();
+        }
+        let scale = 1.0 / (forward * self.outer_angle.to_radians().tan());
+        let coords = TextureCoords {
+            x: (0.5 + 0.5 * outward.dot(&right) * scale) as f32,
+            y: (0.5 - 0.5 * outward.dot(&up) * scale) as f32,
+        };
+        //World space patterns are laid across the slide, as if it sat one unit from the lamp.
+        let position = Point {
+            x: coords.x as f64,
+            y: coords.y as f64,
+            z: 0.0,
+        };
+        gobo.color(&coords, &position, 0.0)
+    }
+}
+
+fn default_light_samples() -> u32 {
+    16
+}
+
 #[derive(Serialize, Deserialize)]
 pub enum Light {
     Directional(DirectionalLight),
     Spherical(SphericalLight),
+    #[serde(deserialize_with = "RectangleLight::deserialize_checked")]
+    Rectangle(RectangleLight),
+    Disk(DiskLight),
+    Sphere(SphereLight),
+    Spot(SpotLight),
+}
+
+pub struct LightSample {
+    pub direction: Vector3,
+    pub distance: f64,
+    pub color: Color,
+    //Irradiance arriving at the hit point from this sample, before the surface's cosine term.
+    pub intensity: f32,
+}
+
+//Irradiance from a one-sided Lambertian emitter of total power `power`, seen from `distance`
+//at `cos_light` to its normal, when sampled uniformly over its area.
+fn area_light_intensity(power: f32, cos_light: f64, distance: f64) -> f32 {
+    power * (cos_light / (PI * distance * distance)) as f32
 }
 
 impl Light {
@@ -328,34 +566,148 @@ impl Light {
         match *self {
             Light::Directional(ref d) => d.color,
             Light::Spherical(ref s) => s.color,
+            Light::Rectangle(ref r) => r.color,
+            Light::Disk(ref d) => d.color,
+            Light::Sphere(ref s) => s.color,
+            Light::Spot(ref s) => s.color,
+        }
+    }
+
+    //The light's colour as it arrives at the hit point, which a spot light's gobo may tint.
+    pub fn color_at(&self, hit_point: &Point) -> Color {
+        match *self {
+            Light::Spot(ref s) => s.color * s.gobo_color(&self.direction_from(hit_point)),
+            _ => self.color(),
+        }
+    }
+
+    pub fn position(&self) -> Option<Point> {
+        match *self {
+            Light::Directional(_) => None,
+            Light::Spherical(ref s) => Some(s.position),
+            Light::Rectangle(ref r) => Some(r.position + (r.u * 0.5) + (r.v * 0.5)),
+            Light::Disk(ref d) => Some(d.position),
+            Light::Sphere(ref s) => Some(s.position),
+            Light::Spot(ref s) => Some(s.position),
         }
     }
 
     pub fn direction_from(&self, hit_point: &Point) -> Vector3 {
         match *self {
             Light::Directional(ref d) => -d.direction,
-            Light::Spherical(ref s) => (s.position - *hit_point).normalise(),
+            _ => (self.position().unwrap() - *hit_point).normalise(),
         }
     }
 
+    //Treats area lights as if all their power came from their centre.
     pub fn intensity(&self, hit_point: &Point) -> f32 {
+        let r2 = self.position()
+            .map(|p| (p - *hit_point).norm())
+            .unwrap_or(1.0);
+        let cos_light = |normal: Vector3| -normal.dot(&self.direction_from(hit_point));
         match *self {
             Light::Directional(ref d) => d.intensity,
-            Light::Spherical(ref s) => {
-                let r2 = (s.position - *hit_point).norm() as f32;
-                s.intensity / (4.0 * ::std::f32::consts::PI * r2)
+            Light::Spherical(ref s) => s.intensity / (4.0 * ::std::f32::consts::PI * r2 as f32),
+            Light::Rectangle(ref r) => area_light_intensity(
+                r.intensity,
+                cos_light(r.u.cross(&r.v).normalise()).max(0.0),
+                r2.sqrt(),
+            ),
+            Light::Disk(ref d) => {
+                area_light_intensity(d.intensity, cos_light(d.normal).max(0.0), r2.sqrt())
+            }
+            Light::Sphere(ref s) => s.intensity / (4.0 * ::std::f32::consts::PI * r2 as f32),
+            Light::Spot(ref s) => {
+                s.falloff(&self.direction_from(hit_point)) * s.intensity
+                    / (4.0 * ::std::f32::consts::PI * r2 as f32)
             }
         }
     }
 
     pub fn distance(&self, hit_point: &Point) -> f64 {
         match *self {
-            Light::Directional(_) => ::std::f64::INFINITY,
-            Light::Spherical(ref s) => (s.position - *hit_point).length(),
+            Light::Directional(_) => f64::INFINITY,
+            _ => (self.position().unwrap() - *hit_point).length(),
+        }
+    }
+
+    pub fn samples(&self) -> u32 {
+        match *self {
+            Light::Rectangle(ref r) => r.samples.max(1),
+            Light::Disk(ref d) => d.samples.max(1),
+            Light::Sphere(ref s) => s.samples.max(1),
+            _ => 1,
+        }
+    }
+
+    //Picks a point on the light as seen from the hit point. Averaging `samples()` of these
+    //gives the light's contribution, including penumbrae once each is shadow tested.
+    pub fn sample<R: Rng>(&self, hit_point: &Point, rng: &mut R) -> Option<LightSample> {
+        let (point, normal, power) = match *self {
+            Light::Rectangle(ref r) => (
+                r.position + (r.u * rng.gen::<f64>()) + (r.v * rng.gen::<f64>()),
+                r.u.cross(&r.v).normalise(),
+                r.intensity,
+            ),
+            Light::Disk(ref d) => {
+                let (x, y) = concentric_disk(rng);
+                let (tangent, bitangent) = orthonormal_basis(&d.normal);
+                (
+                    d.position + (tangent * (x * d.radius)) + (bitangent * (y * d.radius)),
+                    d.normal,
+                    d.intensity,
+                )
+            }
+            Light::Sphere(ref s) => return s.sample(hit_point, rng),
+            _ => {
+                return Some(LightSample {
+                    direction: self.direction_from(hit_point),
+                    distance: self.distance(hit_point),
+                    color: self.color_at(hit_point),
+                    intensity: self.intensity(hit_point),
+                })
+            }
+        };
+        let to_light = point - *hit_point;
+        let distance = to_light.length();
+        let direction = to_light * (1.0 / distance);
+        let cos_light = -normal.dot(&direction);
+        if cos_light <= 0.0 {
+            return None;
         }
+        Some(LightSample {
+            direction,
+            distance,
+            color: self.color(),
+            intensity: area_light_intensity(power, cos_light, distance),
+        })
+    }
+}
+
+impl SphereLight {
+    //Samples the cone of directions the sphere subtends, which wastes no samples on its far
+    //side. Radiance is chosen so that a small sphere matches a point light of equal intensity.
+    fn sample<R: Rng>(&self, hit_point: &Point, rng: &mut R) -> Option<LightSample> {
+        let (direction, distance, solid_angle) =
+            sphere_cone(&self.position, self.radius, hit_point, rng)?;
+        let radiance = self.intensity as f64 / (4.0 * PI * PI * self.radius * self.radius);
+        Some(LightSample {
+            direction,
+            distance,
+            color: self.color,
+            intensity: (radiance * solid_angle) as f32,
+        })
     }
 }
 
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Integrator {
+    #[default]
+    Whitted,
+    Path,
+}
+
 #[derive(Deserialize, Serialize)]
 pub struct Scene {
     pub width: u32,
@@ -363,37 +715,531 @@ pub struct Scene {
     pub fov: f64,
     pub shadow_bias: f64,
     pub max_recursion_depth: u32,
+    #[serde(deserialize_with = "deserialize_elements")]
     pub elements: Vec<Element>,
+    #[serde(deserialize_with = "deserialize_lights")]
     pub lights: Vec<Light>,
     pub camera: Camera,
     pub n_samples: u32,
+    #[serde(default)]
+    pub integrator: Integrator,
+    //In EV stops, applied before tone mapping 8-bit output.
+    #[serde(default)]
+    pub exposure: f32,
+    #[serde(default)]
+    pub tone_mapper: ToneMapper,
+    //Seen by rays that miss everything, and lights diffuse surfaces.
+    #[serde(default)]
+    pub environment: Option<Environment>,
+    //Shown instead of the environment behind everything the camera sees.
+    #[serde(default)]
+    pub background: Option<Background>,
+    //Reflections and refractions then see the environment (or black) instead.
+    #[serde(default)]
+    pub hide_background_in_reflections: bool,
+    //Traces one wavelength per sample so that dispersive dielectrics split light into colours.
+    #[serde(default)]
+    pub spectral: bool,
+    //Textures that materials and gobos can refer to by giving the name in place of a path. The
+    //texture then has the named one's settings; a table of its own settings can't name one.
+    #[serde(default, deserialize_with = "load_textures")]
+    pub textures: BTreeMap<String, Texture>,
+    #[serde(skip)]
+    pub texture_cache: TextureCache,
+    #[serde(skip)]
+    pub bvh: Bvh,
+    //Elements without finite bounds (planes) are tested against every ray.
+    #[serde(skip)]
+    pub unbounded: Vec<usize>,
+    #[serde(skip)]
+    pub emitters: Vec<Emitter>,
 }
 
 pub struct Intersection<'a> {
     pub distance: f64,
     pub element: &'a Element,
+    //The geometry that was hit: the element itself, or one face of a mesh.
+    pub surface: &'a dyn Intersectable,
     //Secret variable stops outside code constructing this; have to use new instead.
     _secret: (),
 }
 
 impl<'a> Intersection<'a> {
     pub fn new<'b>(distance: f64, element: &'b Element) -> Intersection<'b> {
+        Intersection::with_surface(distance, element, element)
+    }
+
+    pub fn with_surface<'b>(
+        distance: f64,
+        element: &'b Element,
+        surface: &'b dyn Intersectable,
+    ) -> Intersection<'b> {
         if !distance.is_finite() {
             panic!("Intersection must have finite distance.");
         }
         Intersection {
             distance: distance,
             element: element,
+            surface,
             _secret: (),
         }
     }
 }
 
+fn intersect_element<'a>(element: &'a Element, ray: &Ray) -> Option<Intersection<'a>> {
+    match *element {
+        Element::Mesh(ref m) => m
+            .model
+            .intersect_face(ray)
+            .map(|(d, f)| Intersection::with_surface(d, element, f)),
+        _ => element
+            .intersect(ray)
+            .map(|d| Intersection::new(d, element)),
+    }
+}
+
 impl Scene {
+    pub fn load(path: &Path) -> Result<Scene, SceneError> {
+        let extension = path.extension().and_then(OsStr::to_str);
+        if extension != Some("json") && extension != Some("yml") && extension != Some("yaml") {
+            return Err(SceneError::UnsupportedFormat {
+                path: path.to_path_buf(),
+            });
+        }
+        let file = File::open(path).map_err(|e| SceneError::Io {
+            path: path.to_path_buf(),
+            error: e,
+        })?;
+        let reader = BufReader::new(file);
+        reset_current_item();
+        let mut scene: Scene = if extension == Some("json") {
+            serde_json::from_reader(reader)
+                .map_err(|e| SceneError::from_json(path.to_path_buf(), e))?
+        } else {
+            serde_yaml::from_reader(reader)
+                .map_err(|e| SceneError::from_yaml(path.to_path_buf(), e))?
+        };
+        scene.load_textures(path)?;
+        scene.prepare();
+        Ok(scene)
+    }
+
+    //Writes the scene in the format its extension names, such that `load` gives it back.
+    pub fn save(&self, path: &Path) -> Result<(), SceneError> {
+        let extension = path.extension().and_then(OsStr::to_str);
+        if extension != Some("json") && extension != Some("yml") && extension != Some("yaml") {
+            return Err(SceneError::UnsupportedFormat {
+                path: path.to_path_buf(),
+            });
+        }
+        let io_error = |e| SceneError::Io {
+            path: path.to_path_buf(),
+            error: e,
+        };
+        let writer = BufWriter::new(File::create(path).map_err(io_error)?);
+        if extension == Some("json") {
+            serde_json::to_writer_pretty(writer, self).map_err(|e| io_error(e.into()))
+        } else {
+            serde_yaml::to_writer(writer, self).map_err(|e| io_error(io::Error::other(e)))
+        }
+    }
+
+    //Resolves every texture to its name in `textures` or else to its path, loading each image
+    //through the cache. Fails on the first image that couldn't be read, or table of settings
+    //naming a shared texture; `path` is the scene file, for the error.
+    fn load_textures(&mut self, path: &Path) -> Result<(), SceneError> {
+        let cache = &mut self.texture_cache;
+        let named = &self.textures;
+        let mut resolve = |texture: &mut Texture, data: bool, item: (&'static str, usize)| {
+            let name = texture.path.to_str().map(String::from);
+            if let Some(shared) = name.as_ref().and_then(|n| named.get(n)) {
+                if texture.table {
+                    return Err(SceneError::NamedTexture {
+                        path: path.to_path_buf(),
+                        item,
+                        name: name.unwrap(),
+                    });
+                }
+                *texture = shared.clone();
+                texture.name = name;
+            }
+            texture.load(cache, data).map_err(|error| SceneError::Texture {
+                path: path.to_path_buf(),
+                item,
+                texture: texture.path.clone(),
+                error: Box::new(error),
+            })
+        };
+        for (i, element) in self.elements.iter_mut().enumerate() {
+            let item = ("elements", i);
+            let material = element.material_mut();
+            if let Coloration::Texture(ref mut texture) = material.coloration {
+                resolve(texture, false, item)?;
+            }
+            if let Some(ref mut map) = material.normal_map {
+                resolve(&mut map.texture, true, item)?;
+            }
+            if let Some(ref mut map) = material.bump_map {
+                resolve(&mut map.texture, true, item)?;
+            }
+        }
+        for (i, light) in self.lights.iter_mut().enumerate() {
+            if let Light::Spot(SpotLight {
+                gobo: Some(Coloration::Texture(ref mut texture)),
+                ..
+            }) = *light
+            {
+                resolve(texture, false, ("lights", i))?;
+            }
+        }
+        Ok(())
+    }
+
+    //Fills in everything derived from the deserialized fields.
+    pub fn prepare(&mut self) {
+        self.camera.rotation_matrix = Camera::calculate_rotation_matrix(
+            self.camera.look_at,
+            self.camera.position,
+            self.camera.up,
+        );
+        if let Some(ref mut environment) = self.environment {
+            environment.prepare();
+        }
+        self.build_bvh();
+        self.emitters = self.elements
+            .iter()
+            .enumerate()
+            .filter_map(|(i, e)| Emitter::new(i, e))
+            .collect();
+    }
+
+    //The scene's lights, plus the sun of a physical sky.
+    pub fn light_sources(&self) -> impl Iterator<Item = &Light> {
+        self.lights
+            .iter()
+            .chain(self.environment.as_ref().and_then(|e| e.sun.as_ref()))
+    }
+
+    fn build_bvh(&mut self) {
+        self.unbounded = self.elements
+            .iter()
+            .enumerate()
+            .filter(|&(_, e)| e.bounds().is_none())
+            .map(|(i, _)| i)
+            .collect();
+        self.bvh = Bvh::new(
+            self.elements
+                .iter()
+                .enumerate()
+                .filter_map(|(i, e)| e.bounds().map(|b| (i, b))),
+        );
+    }
+
     pub fn trace(&self, ray: &Ray) -> Option<Intersection> {
-        self.elements
+        let elements = &self.elements;
+        let bounded = self.bvh
+            .intersect(ray, |i| {
+                intersect_element(&elements[i], ray).map(|hit| (hit.distance, hit))
+            })
+            .map(|(_, hit)| hit);
+        self.unbounded
             .iter()
-            .filter_map(|e| e.intersect(ray).map(|d| Intersection::new(d, e)))
+            .filter_map(|&i| intersect_element(&elements[i], ray))
+            .chain(bounded)
             .min_by(|i1, i2| i1.distance.partial_cmp(&i2.distance).unwrap())
     }
+
+    pub fn occluded(&self, ray: &Ray, max_distance: f64) -> bool {
+        let elements = &self.elements;
+        self.unbounded.iter().any(|&i| {
+            elements[i]
+                .intersect(ray)
+                .map(|d| d < max_distance)
+                .unwrap_or(false)
+        }) || self.bvh.any(ray, max_distance, |i| elements[i].intersect(ray))
+    }
+}
+
+#[cfg(test)]
+impl Scene {
+    //A small scene around a camera at the origin looking down -z, holding the given JSON lists
+    //of elements and lights.
+    pub fn with(elements: &str, lights: &str) -> Scene {
+        let mut scene: Scene = serde_json::from_str(&format!(
+            r#"{{"width": 32, "height": 32, "fov": 90.0, "shadow_bias": 1e-9,
+                "max_recursion_depth": 4, "n_samples": 1,
+                "camera": {{"position": {{"x": 0.0, "y": 0.0, "z": 0.0}},
+                           "look_at": {{"x": 0.0, "y": 0.0, "z": -1.0}}}},
+                "elements": {}, "lights": {}}}"#,
+            elements, lights
+        ))
+        .unwrap();
+        scene.load_textures(Path::new("test.json")).unwrap();
+        scene.prepare();
+        scene
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::rngs::StdRng;
+    use rand::SeedableRng;
+    use texture::Filter;
+
+    fn light(json: &str) -> Light {
+        serde_json::from_str(json).unwrap()
+    }
+
+    fn close(a: f64, b: f64, tolerance: f64) -> bool {
+        (a - b).abs() <= tolerance * b.abs()
+    }
+
+    const WHITE: &str = r#""color": {"red": 1.0, "green": 1.0, "blue": 1.0}"#;
+
+    //Sampling uniformly over the area, each sample's irradiance is P cos / (pi d^2): radiance
+    //P / (pi A) over an area pdf of 1 / A, seen at distance d.
+    fn check_area_light(light: &Light, normal: Vector3, on_light: &dyn Fn(&Point) -> bool) {
+        let hit_point = Point {
+            x: 0.3,
+            y: 0.0,
+            z: -0.2,
+        };
+        let mut rng = StdRng::seed_from_u64(1);
+        let mut total = 0.0;
+        for _ in 0..1000 {
+            let sample = light.sample(&hit_point, &mut rng).unwrap();
+            let point = hit_point + sample.direction * sample.distance;
+            assert!(on_light(&point));
+            let cos = -normal.dot(&sample.direction);
+            let expected = 50.0 * cos / (PI * sample.distance * sample.distance);
+            assert!(close(sample.intensity as f64, expected, 1e-5));
+            total += sample.intensity as f64;
+        }
+        //On average, close to all the power coming from the centre.
+        assert!(close(total / 1000.0, light.intensity(&hit_point) as f64, 0.02));
+    }
+
+    #[test]
+    fn test_rectangle_light_emission() {
+        let rectangle = light(&format!(
+            r#"{{"Rectangle": {{"position": {{"x": -0.5, "y": 4.0, "z": -0.5}},
+                "u": {{"x": 1.0, "y": 0.0, "z": 0.0}}, "v": {{"x": 0.0, "y": 0.0, "z": 1.0}},
+                {}, "intensity": 50.0}}}}"#,
+            WHITE
+        ));
+        let down = Vector3 {
+            x: 0.0,
+            y: -1.0,
+            z: 0.0,
+        };
+        check_area_light(&rectangle, down, &|p| {
+            (p.y - 4.0).abs() < 1e-9 && p.x.abs() <= 0.5 && p.z.abs() <= 0.5
+        });
+    }
+
+    #[test]
+    fn test_disk_light_emission() {
+        let disk = light(&format!(
+            r#"{{"Disk": {{"position": {{"x": 0.0, "y": 4.0, "z": 0.0}},
+                "normal": {{"x": 0.0, "y": -1.0, "z": 0.0}}, "radius": 0.5,
+                {}, "intensity": 50.0}}}}"#,
+            WHITE
+        ));
+        let down = Vector3 {
+            x: 0.0,
+            y: -1.0,
+            z: 0.0,
+        };
+        check_area_light(&disk, down, &|p| {
+            (p.y - 4.0).abs() < 1e-9 && (p.x * p.x + p.z * p.z).sqrt() <= 0.5 + 1e-9
+        });
+        //Nothing reaches points behind it.
+        let behind = Point {
+            x: 0.0,
+            y: 5.0,
+            z: 0.0,
+        };
+        assert!(disk.sample(&behind, &mut StdRng::seed_from_u64(1)).is_none());
+    }
+
+    #[test]
+    fn test_sphere_light_emission() {
+        //Sampled over the cone it subtends, with pdf 1 / solid angle, a sphere gives the same
+        //irradiance as a point light of the same power, P / (4 pi d^2), at any distance.
+        let sphere = light(&format!(
+            r#"{{"Sphere": {{"position": {{"x": 0.0, "y": 4.0, "z": 0.0}}, "radius": 0.5,
+                {}, "intensity": 50.0}}}}"#,
+            WHITE
+        ));
+        let hit_point = Point::zero();
+        let (radius, centre_distance) = (0.5f64, 4.0f64);
+        let cos_max = (1.0 - (radius / centre_distance).powi(2)).sqrt();
+        let solid_angle = 2.0 * PI * (1.0 - cos_max);
+        let radiance = 50.0 / (4.0 * PI * PI * radius * radius);
+        let mut rng = StdRng::seed_from_u64(1);
+        for _ in 0..100 {
+            let sample = sphere.sample(&hit_point, &mut rng).unwrap();
+            let point = hit_point + sample.direction * sample.distance;
+            let on_sphere = (point - Point { x: 0.0, y: 4.0, z: 0.0 }).length();
+            assert!((on_sphere - radius).abs() < 1e-9);
+            assert!(close(sample.intensity as f64, radiance * solid_angle, 1e-5));
+        }
+        let point_light = 50.0 / (4.0 * PI * centre_distance * centre_distance);
+        assert!(close(radiance * solid_angle, point_light, 0.02));
+    }
+
+    #[test]
+    fn test_rectangle_light_with_parallel_edges_is_rejected() {
+        let json = format!(
+            r#"{{"Rectangle": {{"position": {{"x": 0.0, "y": 4.0, "z": 0.0}},
+                "u": {{"x": 1.0, "y": 0.0, "z": 0.0}}, "v": {{"x": 2.0, "y": 0.0, "z": 0.0}},
+                {}, "intensity": 50.0}}}}"#,
+            WHITE
+        );
+        assert!(serde_json::from_str::<Light>(&json).is_err());
+    }
+
+    fn spot(gobo: &str) -> SpotLight {
+        serde_json::from_str(&format!(
+            r#"{{"position": {{"x": 0.0, "y": 0.0, "z": 0.0}},
+                "direction": {{"x": 0.0, "y": -1.0, "z": 0.0}},
+                "inner_angle": 10.0, "outer_angle": 20.0, {}, "intensity": 1.0{}}}"#,
+            WHITE, gobo
+        ))
+        .unwrap()
+    }
+
+    //The direction from a point lit by the spot back to the light, `degrees` off its axis.
+    fn towards_spot(light: &SpotLight, degrees: f64, across: Vector3) -> Vector3 {
+        let (sin, cos) = degrees.to_radians().sin_cos();
+        -(light.direction * cos + across * sin)
+    }
+
+    #[test]
+    fn test_spot_cone_falloff() {
+        let light = spot("");
+        let (right, _) = orthonormal_basis(&light.direction);
+        let falloff = |degrees| light.falloff(&towards_spot(&light, degrees, right));
+        assert_eq!(falloff(0.0), 1.0);
+        assert_eq!(falloff(10.0), 1.0);
+        assert_eq!(falloff(20.0), 0.0);
+        assert_eq!(falloff(30.0), 0.0);
+        let (a, b, c) = (falloff(12.0), falloff(15.0), falloff(18.0));
+        assert!(1.0 > a && a > b && b > c && c > 0.0);
+    }
+
+    #[test]
+    fn test_spot_gobo_fills_the_outer_cone() {
+        //A ramp from black to white along u, projected so that u runs from 0 to 1 across it.
+        let light = spot(
+            r#", "gobo": {"Procedural": {"pattern": "Ramp",
+                "colors": [{"red": 0.0, "green": 0.0, "blue": 0.0},
+                           {"red": 1.0, "green": 1.0, "blue": 1.0}]}}"#,
+        );
+        let (right, up) = orthonormal_basis(&light.direction);
+        let gobo = |degrees, across| light.gobo_color(&towards_spot(&light, degrees, across)).red;
+        assert!((gobo(0.0, right) - 0.5).abs() < 1e-6);
+        assert!((gobo(20.0, right) - 1.0).abs() < 1e-6);
+        assert!(gobo(20.0, -right).abs() < 1e-6);
+        assert!((gobo(20.0, up) - 0.5).abs() < 1e-6);
+        //Halfway across the slide is where the tangent, not the angle, is halved.
+        let half = (0.5 * 20f64.to_radians().tan()).atan().to_degrees();
+        assert!((gobo(half, right) - 0.75).abs() < 1e-6);
+        assert_eq!(spot("").gobo_color(&towards_spot(&light, 5.0, right)).red, 1.0);
+    }
+
+    fn textured_scene(textures: &str, coloration: &str) -> Scene {
+        serde_json::from_str(&format!(
+            r#"{{"width": 32, "height": 32, "fov": 90.0, "shadow_bias": 1e-9,
+                "max_recursion_depth": 4, "n_samples": 1,
+                "camera": {{"position": {{"x": 0.0, "y": 0.0, "z": 0.0}},
+                           "look_at": {{"x": 0.0, "y": 0.0, "z": -1.0}}}},
+                "textures": {},
+                "elements": [{{"Sphere": {{"centre": {{"x": 0.0, "y": 0.0, "z": -5.0}},
+                    "radius": 1.0, "material": {{"coloration": {{"Texture": {}}},
+                    "albedo": 0.18, "surface": "Diffuse"}}}}}}],
+                "lights": []}}"#,
+            textures, coloration
+        ))
+        .unwrap()
+    }
+
+    #[test]
+    fn test_texture_errors_name_the_element() {
+        let textures = r#"{"check": {"path": "scenes/checkerboard.png", "filter": "Trilinear"}}"#;
+        let mut scene = textured_scene(textures, r#"{"path": "check", "filter": "Bilinear"}"#);
+        match scene.load_textures(Path::new("scene.json")) {
+            Err(SceneError::NamedTexture { item, ref name, .. }) => {
+                assert_eq!(item, ("elements", 0));
+                assert_eq!(name, "check");
+            }
+            _ => panic!("expected a named texture error"),
+        }
+
+        let mut scene = textured_scene("{}", r#""scenes/missing.png""#);
+        match scene.load_textures(Path::new("scene.json")) {
+            Err(SceneError::Texture { item, .. }) => assert_eq!(item, ("elements", 0)),
+            _ => panic!("expected a texture error"),
+        }
+
+        let mut scene = textured_scene(textures, r#""check""#);
+        scene.load_textures(Path::new("scene.json")).unwrap();
+        match scene.elements[0].material().coloration {
+            Coloration::Texture(ref texture) => match texture.filter {
+                Filter::Trilinear => {}
+                _ => panic!("expected the named texture's filter"),
+            },
+            _ => panic!("expected a texture"),
+        }
+    }
+
+    #[test]
+    fn test_saved_scenes_load_back_the_same() {
+        let dir = ::std::env::temp_dir().join(format!("raytracer-{}", ::std::process::id()));
+        ::std::fs::create_dir_all(&dir).unwrap();
+        let obj = dir.join("triangle.obj");
+        ::std::fs::write(&obj, "v 0 0 -3\nv 1 0 -3\nv 0 1 -3\nf 1 2 3\n").unwrap();
+        let material = |coloration: &str| {
+            format!(
+                r#"{{"coloration": {}, "albedo": 0.18, "surface": "Diffuse"}}"#,
+                coloration
+            )
+        };
+        let json = format!(
+            r#"{{"width": 32, "height": 32, "fov": 90.0, "shadow_bias": 1e-9,
+                "max_recursion_depth": 4, "n_samples": 1,
+                "camera": {{"position": {{"x": 0.0, "y": 0.0, "z": 0.0}},
+                           "look_at": {{"x": 0.0, "y": 0.0, "z": -1.0}}}},
+                "textures": {{"check": {{"path": "scenes/checkerboard.png", "wrap": "Mirror"}}}},
+                "elements": [
+                    {{"Sphere": {{"centre": {{"x": 0.0, "y": 0.0, "z": -5.0}}, "radius": 1.0,
+                        "material": {}}}}},
+                    {{"Sphere": {{"centre": {{"x": 2.0, "y": 0.0, "z": -5.0}}, "radius": 1.0,
+                        "material": {}}}}},
+                    {{"Mesh": {{"path": {:?}, "material": {}}}}}],
+                "lights": [{{"Spot": {{"position": {{"x": 0.0, "y": 5.0, "z": -5.0}},
+                    "direction": {{"x": 0.0, "y": -1.0, "z": 0.0}},
+                    "inner_angle": 10.0, "outer_angle": 20.0, {}, "intensity": 100.0,
+                    "gobo": {{"Texture": "check"}}}}}}]}}"#,
+            material(r#"{"Texture": {"path": "scenes/checkerboard.png", "filter": "Bilinear"}}"#),
+            material(r#"{"Texture": "check"}"#),
+            obj.to_str().unwrap(),
+            material(r#"{"Color": {"red": 1.0, "green": 0.5, "blue": 0.25}}"#),
+            WHITE
+        );
+        let original = dir.join("original.json");
+        ::std::fs::write(&original, json).unwrap();
+        let scene = Scene::load(&original).unwrap();
+        let expected = serde_json::to_string(&scene).unwrap();
+        assert!(expected.contains(r#""coloration":{"Texture":"check"}"#));
+        assert!(expected.contains(r#""gobo":{"Texture":"check"}"#));
+        for name in &["saved.json", "saved.yml"] {
+            let saved = dir.join(name);
+            scene.save(&saved).unwrap();
+            let reloaded = Scene::load(&saved).unwrap();
+            assert_eq!(serde_json::to_string(&reloaded).unwrap(), expected);
+        }
+        ::std::fs::remove_dir_all(&dir).unwrap();
+    }
 }
@@ -0,0 +1,197 @@
+use framebuffer::Framebuffer;
+use environment::uv_to_direction;
+use scene::{Color, DirectionalLight};
+use std::f64::consts::PI;
+use vector::Vector3;
+
+//Preetham, Shirley and Smits' analytic daylight model ("A Practical Analytic Model for
+//Daylight", 1999). Sky radiance comes out in kcd/m^2.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Sky {
+    //Degrees above the horizon.
+    pub sun_elevation: f64,
+    //Degrees clockwise from -z (the default camera's view) towards +x.
+    #[serde(default)]
+    pub sun_azimuth: f64,
+    //Haziness, from about 2 (very clear) to 10 (hazy).
+    #[serde(default = "default_turbidity")]
+    pub turbidity: f64,
+    //Irradiance of the sun before the atmosphere; zero leaves out the sun light.
+    #[serde(default = "default_sun_intensity")]
+    pub sun_intensity: f32,
+}
+
+fn default_turbidity() -> f64 {
+    3.0
+}
+
+fn default_sun_intensity() -> f32 {
+    100.0
+}
+
+//Coefficients A to E of the Perez distribution for luminance, x and y chromaticity.
+type Perez = [f64; 5];
+
+fn perez(distribution: &Perez, theta: f64, gamma: f64) -> f64 {
+    let [a, b, c, d, e] = *distribution;
+    (1.0 + a * (b / theta.cos()).exp()) * (1.0 + c * (d * gamma).exp() + e * gamma.cos().powi(2))
+}
+
+impl Sky {
+    pub fn sun_direction(&self) -> Vector3 {
+        let elevation = self.sun_elevation.to_radians();
+        let azimuth = self.sun_azimuth.to_radians();
+        Vector3 {
+            x: elevation.cos() * azimuth.sin(),
+            y: elevation.sin(),
+            z: -elevation.cos() * azimuth.cos(),
+        }
+    }
+
+    fn distributions(&self) -> (Perez, Perez, Perez) {
+        let t = self.turbidity;
+        (
+            [
+                0.1787 * t - 1.4630,
+                -0.3554 * t + 0.4275,
+                -0.0227 * t + 5.3251,
+                0.1206 * t - 2.5771,
+                -0.0670 * t + 0.3703,
+            ],
+            [
+                -0.0193 * t - 0.2592,
+                -0.0665 * t + 0.0008,
+                -0.0004 * t + 0.2125,
+                -0.0641 * t - 0.8989,
+                -0.0033 * t + 0.0452,
+            ],
+            [
+                -0.0167 * t - 0.2608,
+                -0.0950 * t + 0.0092,
+                -0.0079 * t + 0.2102,
+                -0.0441 * t - 1.6537,
+                -0.0109 * t + 0.0529,
+            ],
+        )
+    }
+
+    //Luminance and chromaticity straight up.
+    fn zenith(&self) -> (f64, f64, f64) {
+        let t = self.turbidity;
+        let theta = self.sun_zenith_angle();
+        let chi = (4.0 / 9.0 - t / 120.0) * (PI - 2.0 * theta);
+        let luminance = (4.0453 * t - 4.9710) * chi.tan() - 0.2155 * t + 2.4192;
+        let (t2, th2, th3) = (t * t, theta * theta, theta * theta * theta);
+        let x = t2 * (0.00166 * th3 - 0.00375 * th2 + 0.00209 * theta)
+            + t * (-0.02903 * th3 + 0.06377 * th2 - 0.03202 * theta + 0.00394)
+            + (0.11693 * th3 - 0.21196 * th2 + 0.06052 * theta + 0.25886);
+        let y = t2 * (0.00275 * th3 - 0.00610 * th2 + 0.00317 * theta)
+            + t * (-0.04214 * th3 + 0.08970 * th2 - 0.04153 * theta + 0.00516)
+            + (0.15346 * th3 - 0.26756 * th2 + 0.06670 * theta + 0.26688);
+        (luminance, x, y)
+    }
+
+    fn sun_zenith_angle(&self) -> f64 {
+        (PI / 2.0 - self.sun_elevation.to_radians()).clamp(0.0, PI / 2.0)
+    }
+
+    //Below the horizon, where the model is undefined, the sky keeps its horizon colour.
+    pub fn color(&self, direction: &Vector3) -> Color {
+        let (luminance_distribution, x_distribution, y_distribution) = self.distributions();
+        let (zenith_luminance, zenith_x, zenith_y) = self.zenith();
+        let sun_theta = self.sun_zenith_angle();
+        let theta = direction.y.clamp(0.001, 1.0).acos();
+        let gamma = direction.dot(&self.sun_direction()).clamp(-1.0, 1.0).acos();
+        let relative = |distribution: &Perez| {
+            perez(distribution, theta, gamma) / perez(distribution, 0.0, sun_theta)
+        };
+        xyy_to_rgb(
+            zenith_x * relative(&x_distribution),
+            zenith_y * relative(&y_distribution),
+            zenith_luminance * relative(&luminance_distribution),
+        )
+    }
+
+    //The sky as an equirectangular image, without the sun's disc, which `sun` lights instead.
+    pub fn render(&self, width: u32, height: u32) -> Framebuffer {
+        let mut image = Framebuffer::new(width, height);
+        for y in 0..height {
+            for x in 0..width {
+                let direction = uv_to_direction(
+                    (x as f64 + 0.5) / width as f64,
+                    (y as f64 + 0.5) / height as f64,
+                );
+                image.put(x, y, self.color(&direction));
+            }
+        }
+        image
+    }
+
+    //Sunlight after Rayleigh and aerosol scattering along its path through the atmosphere,
+    //evaluated at 680, 550 and 440nm for red, green and blue.
+    pub fn sun(&self) -> Option<DirectionalLight> {
+        if self.sun_elevation <= 0.0 || self.sun_intensity <= 0.0 {
+            return None;
+        }
+        let theta = self.sun_zenith_angle();
+        let air_mass = 1.0 / (theta.cos() + 0.15 * (93.885 - theta.to_degrees()).powf(-1.253));
+        let beta = 0.04608 * self.turbidity - 0.04586;
+        let transmittance = |wavelength: f64| {
+            let rayleigh = (-0.008735 * wavelength.powf(-4.08) * air_mass).exp();
+            let aerosol = (-beta * wavelength.powf(-1.3) * air_mass).exp();
+            (rayleigh * aerosol) as f32
+        };
+        Some(DirectionalLight {
+            direction: -self.sun_direction(),
+            color: Color {
+                red: transmittance(0.68),
+                green: transmittance(0.55),
+                blue: transmittance(0.44),
+            },
+            intensity: self.sun_intensity,
+        })
+    }
+}
+
+fn xyy_to_rgb(x: f64, y: f64, luminance: f64) -> Color {
+    let big_x = x * luminance / y;
+    let big_z = (1.0 - x - y) * luminance / y;
+    Color {
+        red: (3.2406 * big_x - 1.5372 * luminance - 0.4986 * big_z).max(0.0) as f32,
+        green: (-0.9689 * big_x + 1.8758 * luminance + 0.0415 * big_z).max(0.0) as f32,
+        blue: (0.0557 * big_x - 0.2040 * luminance + 1.0570 * big_z).max(0.0) as f32,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sky(sun_elevation: f64) -> Sky {
+        Sky {
+            sun_elevation,
+            sun_azimuth: 0.0,
+            turbidity: 3.0,
+            sun_intensity: 1.0,
+        }
+    }
+
+    #[test]
+    fn test_low_sun_is_redder() {
+        let high = sky(60.0).sun().unwrap().color;
+        let low = sky(5.0).sun().unwrap().color;
+        assert!(low.blue / low.red < high.blue / high.red);
+        assert!(sky(-5.0).sun().is_none());
+    }
+
+    #[test]
+    fn test_clear_sky_is_blue_overhead() {
+        let sky = sky(30.0);
+        let zenith = sky.color(&Vector3 {
+            x: 0.0,
+            y: 1.0,
+            z: 0.0,
+        });
+        assert!(zenith.blue > zenith.red);
+    }
+}
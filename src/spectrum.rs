@@ -0,0 +1,110 @@
+use rand::Rng;
+use scene::Color;
+
+//Wavelengths in nanometres traced in spectral mode.
+pub const MIN_WAVELENGTH: f64 = 380.0;
+pub const MAX_WAVELENGTH: f64 = 780.0;
+
+//Average of `wavelength_rgb` over the visible range, which maps to white.
+const AVERAGE_RGB: [f64; 3] = [0.320_906_7, 0.253_871_6, 0.242_623_9];
+
+//How a refractive index varies with wavelength.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub enum Dispersion {
+    //n = a + b / l^2 + c / l^4, with l in micrometres.
+    Cauchy {
+        a: f64,
+        b: f64,
+        #[serde(default)]
+        c: f64,
+    },
+    //n^2 = 1 + sum(b[i] * l^2 / (l^2 - c[i])), with l in micrometres, as in glass catalogues.
+    Sellmeier { b: [f64; 3], c: [f64; 3] },
+}
+
+impl Dispersion {
+    pub fn index(&self, wavelength: f64) -> f32 {
+        let l = wavelength / 1000.0;
+        let l2 = l * l;
+        let index = match *self {
+            Dispersion::Cauchy { a, b, c } => a + b / l2 + c / (l2 * l2),
+            Dispersion::Sellmeier { ref b, ref c } => {
+                (1.0 + (0..3).map(|i| b[i] * l2 / (l2 - c[i])).sum::<f64>()).sqrt()
+            }
+        };
+        index as f32
+    }
+}
+
+//Picks a wavelength from the `stratum`th of `strata` equal bands, so that the samples of a pixel
+//cover the spectrum evenly rather than clumping into colour noise.
+pub fn sample_wavelength<R: Rng>(stratum: u32, strata: u32, rng: &mut R) -> f64 {
+    let u = (stratum as f64 + rng.gen::<f64>()) / strata as f64;
+    MIN_WAVELENGTH + (MAX_WAVELENGTH - MIN_WAVELENGTH) * u
+}
+
+fn lobe(wavelength: f64, mean: f64, below: f64, above: f64) -> f64 {
+    let t = (wavelength - mean) / if wavelength < mean { below } else { above };
+    (-0.5 * t * t).exp()
+}
+
+//The CIE 1931 colour matching functions, as fitted by Wyman, Sloan and Shirley (2013).
+pub fn cie_xyz(wavelength: f64) -> (f64, f64, f64) {
+    let l = wavelength;
+    (
+        1.056 * lobe(l, 599.8, 37.9, 31.0) + 0.362 * lobe(l, 442.0, 16.0, 26.7)
+            - 0.065 * lobe(l, 501.1, 20.4, 26.2),
+        0.821 * lobe(l, 568.8, 46.9, 40.5) + 0.286 * lobe(l, 530.9, 16.3, 31.1),
+        1.217 * lobe(l, 437.0, 11.8, 36.0) + 0.681 * lobe(l, 459.0, 26.0, 13.8),
+    )
+}
+
+//Linear sRGB of a single wavelength; negative where it lies outside the sRGB gamut.
+fn wavelength_rgb(wavelength: f64) -> [f64; 3] {
+    let (x, y, z) = cie_xyz(wavelength);
+    [
+        3.2406 * x - 1.5372 * y - 0.4986 * z,
+        -0.9689 * x + 1.8758 * y + 0.0415 * z,
+        0.0557 * x - 0.2040 * y + 1.0570 * z,
+    ]
+}
+
+//Multiplies what one uniformly chosen wavelength saw, so that the average over many wavelengths
+//gives the same colours as tracing in RGB wherever nothing disperses.
+pub fn wavelength_weight(wavelength: f64) -> Color {
+    let rgb = wavelength_rgb(wavelength);
+    Color {
+        red: (rgb[0] / AVERAGE_RGB[0]) as f32,
+        green: (rgb[1] / AVERAGE_RGB[1]) as f32,
+        blue: (rgb[2] / AVERAGE_RGB[2]) as f32,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_wavelength_weights_average_to_white() {
+        let n = 4000;
+        let mut total = Color::black();
+        for i in 0..n {
+            let t = (i as f64 + 0.5) / n as f64;
+            total = total + wavelength_weight(MIN_WAVELENGTH + (MAX_WAVELENGTH - MIN_WAVELENGTH) * t);
+        }
+        let average = total * (1.0 / n as f32);
+        assert!((average.red - 1.0).abs() < 1e-3);
+        assert!((average.green - 1.0).abs() < 1e-3);
+        assert!((average.blue - 1.0).abs() < 1e-3);
+    }
+
+    #[test]
+    fn test_bk7_disperses() {
+        let bk7 = Dispersion::Sellmeier {
+            b: [1.039_612_12, 0.231_792_344, 1.010_469_45],
+            c: [0.006_000_698_67, 0.020_017_914_4, 103.560_653],
+        };
+        assert!((bk7.index(587.6) - 1.5168).abs() < 1e-3);
+        assert!(bk7.index(450.0) > bk7.index(650.0));
+    }
+}
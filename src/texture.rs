@@ -0,0 +1,353 @@
+use framebuffer::Framebuffer;
+use image::ImageResult;
+use rendering::TextureCoords;
+use scene::Color;
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default)]
+pub enum Filter {
+    #[default]
+    Nearest,
+    Bilinear,
+    //Bilinear lookups in the two mip levels nearest the footprint, blended.
+    Trilinear,
+}
+
+//What happens to texture coordinates outside [0, 1).
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default)]
+pub enum Wrap {
+    #[default]
+    Repeat,
+    Clamp,
+    Mirror,
+}
+
+fn default_uv_scale() -> TextureCoords {
+    TextureCoords { x: 1.0, y: 1.0 }
+}
+
+fn default_uv_offset() -> TextureCoords {
+    TextureCoords { x: 0.0, y: 0.0 }
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct Texture {
+    pub path: PathBuf,
+    #[serde(default)]
+    pub filter: Filter,
+    #[serde(default)]
+    pub wrap: Wrap,
+    //Applied to the element's texture coordinates in this order: scale, then rotation (degrees,
+    //anticlockwise), then offset.
+    #[serde(default = "default_uv_scale")]
+    pub scale: TextureCoords,
+    #[serde(default)]
+    pub rotation: f32,
+    #[serde(default = "default_uv_offset")]
+    pub offset: TextureCoords,
+    //Set when the texture came from the scene's named textures rather than its own path.
+    #[serde(skip)]
+    pub name: Option<String>,
+    //Set when the texture was given as a table rather than just a path. Only a bare path can
+    //name one of the scene's textures, whose settings it then takes.
+    #[serde(skip)]
+    pub table: bool,
+    //The image and each halving of it down to a single texel, shared through the scene's cache.
+    #[serde(skip)]
+    levels: Arc<Vec<Framebuffer>>,
+}
+
+//A texture is either just its path (or the name of one of the scene's textures) or a table giving
+//the path and how to sample it.
+#[derive(Deserialize)]
+#[serde(untagged)]
+pub enum TextureSource {
+    Path(PathBuf),
+    Texture(Texture),
+}
+
+impl TextureSource {
+    pub fn into_texture(self) -> Texture {
+        match self {
+            TextureSource::Path(path) => Texture::from_path(path),
+            TextureSource::Texture(texture) => Texture {
+                table: true,
+                ..texture
+            },
+        }
+    }
+}
+
+//Images keyed by path, and whether they hold colours or data, so each is read and mipmapped
+//once however many materials use it.
+#[derive(Default)]
+pub struct TextureCache {
+    images: HashMap<(PathBuf, bool), Arc<Vec<Framebuffer>>>,
+}
+
+impl TextureCache {
+    //The whole mip chain is built up front; it costs a third more memory than the image alone.
+    pub fn get(&mut self, path: &Path, data: bool) -> ImageResult<Arc<Vec<Framebuffer>>> {
+        let key = (path.to_path_buf(), data);
+        if let Some(levels) = self.images.get(&key) {
+            return Ok(levels.clone());
+        }
+        let image = if data {
+            Framebuffer::load_data(path)?
+        } else {
+            Framebuffer::load(path)?
+        };
+        let mut levels = vec![image];
+        while let Some(level) = levels.last().and_then(downsample) {
+            levels.push(level);
+        }
+        let levels = Arc::new(levels);
+        self.images.insert(key, levels.clone());
+        Ok(levels)
+    }
+}
+
+impl Texture {
+    pub fn from_path(path: PathBuf) -> Texture {
+        Texture {
+            path,
+            filter: Filter::default(),
+            wrap: Wrap::default(),
+            scale: default_uv_scale(),
+            rotation: 0.0,
+            offset: default_uv_offset(),
+            name: None,
+            table: false,
+            levels: Arc::new(Vec::new()),
+        }
+    }
+
+    //A texture of just this image, sampled with the default settings.
+    #[cfg(test)]
+    pub fn from_image(image: Framebuffer) -> Texture {
+        let mut levels = vec![image];
+        while let Some(level) = levels.last().and_then(downsample) {
+            levels.push(level);
+        }
+        Texture {
+            levels: Arc::new(levels),
+            ..Texture::from_path(PathBuf::new())
+        }
+    }
+
+    //Points the texture at its image, which must be done before it is sampled. `data` textures,
+    //like normal maps, keep the values stored in 8-bit images rather than decoding them as sRGB.
+    pub fn load(&mut self, cache: &mut TextureCache, data: bool) -> ImageResult<()> {
+        self.levels = cache.get(&self.path, data)?;
+        Ok(())
+    }
+
+    //Rate of change of the red channel along each texture coordinate, over about one texel. The
+    //image is always sampled bilinearly here, as nearest texels would give a staircase whose
+    //slope is zero or a spike.
+    pub fn gradient(&self, texture_coords: &TextureCoords) -> (f32, f32) {
+        let base = &self.levels[0];
+        let stretch = self.scale.x.abs().max(self.scale.y.abs()).max(1e-6);
+        let step = 1.0 / (base.width.max(base.height) as f32 * stretch);
+        let at = |dx: f32, dy: f32| {
+            let (u, v) = self.transform(&TextureCoords {
+                x: texture_coords.x + dx,
+                y: texture_coords.y + dy,
+            });
+            self.bilinear(0, u, v).red
+        };
+        (
+            (at(step, 0.0) - at(-step, 0.0)) / (2.0 * step),
+            (at(0.0, step) - at(0.0, -step)) / (2.0 * step),
+        )
+    }
+
+    //`footprint` is the width, in the element's texture coordinates, of the area being shaded.
+    pub fn color(&self, texture_coords: &TextureCoords, footprint: f32) -> Color {
+        let (u, v) = self.transform(texture_coords);
+        match self.filter {
+            Filter::Nearest => self.nearest(0, u, v),
+            Filter::Bilinear => self.bilinear(0, u, v),
+            Filter::Trilinear => {
+                let base = &self.levels[0];
+                let stretch = self.scale.x.abs().max(self.scale.y.abs());
+                let texels = footprint * stretch * base.width.max(base.height) as f32;
+                let top = (self.levels.len() - 1) as f32;
+                let lod = texels.max(1.0).log2().min(top);
+                let level = lod.floor() as usize;
+                let f = lod - level as f32;
+                if f <= 0.0 {
+                    return self.bilinear(level, u, v);
+                }
+                self.bilinear(level, u, v) * (1.0 - f) + self.bilinear(level + 1, u, v) * f
+            }
+        }
+    }
+
+    fn transform(&self, texture_coords: &TextureCoords) -> (f32, f32) {
+        let x = texture_coords.x * self.scale.x;
+        let y = texture_coords.y * self.scale.y;
+        let (sin, cos) = self.rotation.to_radians().sin_cos();
+        (
+            x * cos - y * sin + self.offset.x,
+            x * sin + y * cos + self.offset.y,
+        )
+    }
+
+    fn texel(&self, level: usize, x: i64, y: i64) -> Color {
+        let image = &self.levels[level];
+        image.get(
+            wrap(x, image.width, self.wrap),
+            wrap(y, image.height, self.wrap),
+        )
+    }
+
+    fn nearest(&self, level: usize, u: f32, v: f32) -> Color {
+        let image = &self.levels[level];
+        let x = (u * image.width as f32).floor() as i64;
+        let y = (v * image.height as f32).floor() as i64;
+        self.texel(level, x, y)
+    }
+
+    fn bilinear(&self, level: usize, u: f32, v: f32) -> Color {
+        let image = &self.levels[level];
+        let x = u * image.width as f32 - 0.5;
+        let y = v * image.height as f32 - 0.5;
+        let (x0, y0) = (x.floor(), y.floor());
+        let (fx, fy) = (x - x0, y - y0);
+        let (x0, y0) = (x0 as i64, y0 as i64);
+        let top = self.texel(level, x0, y0) * (1.0 - fx) + self.texel(level, x0 + 1, y0) * fx;
+        let bottom =
+            self.texel(level, x0, y0 + 1) * (1.0 - fx) + self.texel(level, x0 + 1, y0 + 1) * fx;
+        top * (1.0 - fy) + bottom * fy
+    }
+}
+
+fn wrap(i: i64, size: u32, mode: Wrap) -> u32 {
+    let n = size as i64;
+    let wrapped = match mode {
+        Wrap::Repeat => i.rem_euclid(n),
+        Wrap::Clamp => i.max(0).min(n - 1),
+        Wrap::Mirror => {
+            let m = i.rem_euclid(2 * n);
+            if m < n {
+                m
+            } else {
+                2 * n - 1 - m
+            }
+        }
+    };
+    wrapped as u32
+}
+
+//The next mip level, each texel averaging (up to) four of the one above; None past 1x1.
+fn downsample(image: &Framebuffer) -> Option<Framebuffer> {
+    if image.width == 1 && image.height == 1 {
+        return None;
+    }
+    let width = (image.width / 2).max(1);
+    let height = (image.height / 2).max(1);
+    let mut level = Framebuffer::new(width, height);
+    for y in 0..height {
+        for x in 0..width {
+            let x0 = (2 * x).min(image.width - 1);
+            let x1 = (2 * x + 1).min(image.width - 1);
+            let y0 = (2 * y).min(image.height - 1);
+            let y1 = (2 * y + 1).min(image.height - 1);
+            let sum = image.get(x0, y0) + image.get(x1, y0) + image.get(x0, y1) + image.get(x1, y1);
+            level.put(x, y, sum * 0.25);
+        }
+    }
+    Some(level)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn grey(value: f32) -> Color {
+        Color {
+            red: value,
+            green: value,
+            blue: value,
+        }
+    }
+
+    //A 2x2 texture, black on the left and white on the right.
+    fn stripes(filter: Filter, wrap: Wrap) -> Texture {
+        let mut image = Framebuffer::new(2, 2);
+        image.put(1, 0, grey(1.0));
+        image.put(1, 1, grey(1.0));
+        let mut texture = Texture::from_image(image);
+        texture.filter = filter;
+        texture.wrap = wrap;
+        texture
+    }
+
+    fn at(texture: &Texture, u: f32, footprint: f32) -> f32 {
+        texture.color(&TextureCoords { x: u, y: 0.5 }, footprint).red
+    }
+
+    #[test]
+    fn test_wrap_modes() {
+        assert_eq!(wrap(-1, 4, Wrap::Repeat), 3);
+        assert_eq!(wrap(5, 4, Wrap::Clamp), 3);
+        assert_eq!(wrap(-1, 4, Wrap::Mirror), 0);
+        assert_eq!(wrap(4, 4, Wrap::Mirror), 3);
+        assert_eq!(wrap(8, 4, Wrap::Mirror), 0);
+    }
+
+    #[test]
+    fn test_bilinear_blends_across_texels_and_edges() {
+        let repeat = stripes(Filter::Bilinear, Wrap::Repeat);
+        assert!((at(&repeat, 0.5, 0.0) - 0.5).abs() < 1e-6);
+        assert!((at(&repeat, 0.0, 0.0) - 0.5).abs() < 1e-6);
+        let clamp = stripes(Filter::Bilinear, Wrap::Clamp);
+        assert_eq!(at(&clamp, 0.0, 0.0), 0.0);
+        assert_eq!(at(&stripes(Filter::Nearest, Wrap::Repeat), 0.6, 0.0), 1.0);
+    }
+
+    #[test]
+    fn test_trilinear_averages_wide_footprints() {
+        let texture = stripes(Filter::Trilinear, Wrap::Clamp);
+        assert_eq!(texture.levels.len(), 2);
+        assert_eq!(at(&texture, 0.25, 0.0), 0.0);
+        assert!((at(&texture, 0.25, 1.0) - 0.5).abs() < 1e-6);
+    }
+
+    #[test]
+    fn test_uv_transform() {
+        let mut texture = stripes(Filter::Nearest, Wrap::Repeat);
+        texture.rotation = 90.0;
+        //Rotating a quarter turn carries v onto u, so the stripes now vary along v.
+        let coords = |u, v| texture.color(&TextureCoords { x: u, y: v }, 0.0).red;
+        assert_eq!(coords(0.5, 0.25), 1.0);
+        assert_eq!(coords(0.5, 0.75), 0.0);
+    }
+
+    #[test]
+    fn test_gradient_measures_slope_along_u() {
+        //Left half black, right half white: from one edge to the other red climbs by 1.
+        let texture = stripes(Filter::Bilinear, Wrap::Clamp);
+        let (du, dv) = texture.gradient(&TextureCoords { x: 0.5, y: 0.5 });
+        assert!((du - 1.0).abs() < 1e-5);
+        assert_eq!(dv, 0.0);
+    }
+
+    #[test]
+    fn test_gradient_is_smooth_whatever_the_filter() {
+        //Four texels, black black white white. Differences of nearest lookups would be 0 at
+        //u = 0.2 and 2 at u = 0.3, faceting a bump map; bilinear lookups ramp up in between.
+        let mut image = Framebuffer::new(4, 1);
+        image.put(2, 0, grey(1.0));
+        image.put(3, 0, grey(1.0));
+        let mut texture = Texture::from_image(image);
+        texture.wrap = Wrap::Clamp;
+        let du = |u| texture.gradient(&TextureCoords { x: u, y: 0.5 }).0;
+        assert!((du(0.2) - 0.6).abs() < 1e-5);
+        assert!((du(0.3) - 1.4).abs() < 1e-5);
+        assert!((du(0.5) - 2.0).abs() < 1e-5);
+    }
+}
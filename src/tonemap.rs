@@ -0,0 +1,135 @@
+use scene::Color;
+use std::str::FromStr;
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum ToneMapper {
+    #[default]
+    Clamp,
+    Reinhard,
+    ExtendedReinhard,
+    Aces,
+    Uncharted2,
+}
+
+impl FromStr for ToneMapper {
+    type Err = String;
+
+    fn from_str(name: &str) -> Result<ToneMapper, String> {
+        match name {
+            "clamp" => Ok(ToneMapper::Clamp),
+            "reinhard" => Ok(ToneMapper::Reinhard),
+            "extended_reinhard" => Ok(ToneMapper::ExtendedReinhard),
+            "aces" => Ok(ToneMapper::Aces),
+            "uncharted2" => Ok(ToneMapper::Uncharted2),
+            _ => Err(format!("unknown tone mapper: {}", name)),
+        }
+    }
+}
+
+pub const TONE_MAPPERS: &[&str] = &[
+    "clamp",
+    "reinhard",
+    "extended_reinhard",
+    "aces",
+    "uncharted2",
+];
+
+impl ToneMapper {
+    // Maps linear radiance to [0, 1]. `white` is the luminance that the extended Reinhard
+    // operator maps to 1, usually the brightest pixel in the image.
+    pub fn map(&self, color: Color, white: f32) -> Color {
+        match *self {
+            ToneMapper::Clamp => color.clamp(),
+            ToneMapper::Reinhard => scale_luminance(color, |l| l / (1.0 + l)),
+            ToneMapper::ExtendedReinhard => {
+                let white2 = (white * white).max(1e-6);
+                scale_luminance(color, |l| l * (1.0 + l / white2) / (1.0 + l))
+            }
+            ToneMapper::Aces => per_channel(color, aces),
+            ToneMapper::Uncharted2 => {
+                const EXPOSURE_BIAS: f32 = 2.0;
+                const WHITE: f32 = 11.2;
+                let white_scale = 1.0 / uncharted2(WHITE);
+                per_channel(color, |c| uncharted2(c * EXPOSURE_BIAS) * white_scale)
+            }
+        }.clamp()
+    }
+}
+
+fn scale_luminance<F: Fn(f32) -> f32>(color: Color, curve: F) -> Color {
+    let luminance = color.luminance();
+    if luminance <= 0.0 {
+        return Color::black();
+    }
+    color * (curve(luminance) / luminance)
+}
+
+fn per_channel<F: Fn(f32) -> f32>(color: Color, curve: F) -> Color {
+    Color {
+        red: curve(color.red),
+        green: curve(color.green),
+        blue: curve(color.blue),
+    }
+}
+
+// Krzysztof Narkowicz's fit of the ACES filmic curve.
+fn aces(x: f32) -> f32 {
+    (x * (2.51 * x + 0.03)) / (x * (2.43 * x + 0.59) + 0.14)
+}
+
+// John Hable's filmic curve from Uncharted 2.
+fn uncharted2(x: f32) -> f32 {
+    let a = 0.15;
+    let b = 0.50;
+    let c = 0.10;
+    let d = 0.20;
+    let e = 0.02;
+    let f = 0.30;
+    ((x * (a * x + c * b) + d * e) / (x * (a * x + b) + d * f)) - e / f
+}
+
+pub fn srgb_encode(linear: f32) -> f32 {
+    if linear <= 0.003_130_8 {
+        linear * 12.92
+    } else {
+        1.055 * linear.powf(1.0 / 2.4) - 0.055
+    }
+}
+
+pub fn srgb_decode(encoded: f32) -> f32 {
+    if encoded <= 0.040_45 {
+        encoded / 12.92
+    } else {
+        ((encoded + 0.055) / 1.055).powf(2.4)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_srgb_round_trip() {
+        for i in 0..=255 {
+            let encoded = i as f32 / 255.0;
+            assert!((srgb_encode(srgb_decode(encoded)) - encoded).abs() < 1e-5);
+        }
+        assert!((srgb_encode(0.5) - 0.735_357).abs() < 1e-5);
+    }
+
+    #[test]
+    fn test_tone_mappers_stay_in_range_and_are_monotonic() {
+        for name in TONE_MAPPERS {
+            let tone_mapper = name.parse::<ToneMapper>().unwrap();
+            let mut previous = 0.0;
+            for i in 0..100 {
+                let value = i as f32 * 0.25;
+                let mapped = tone_mapper.map(Color::white() * value, 20.0);
+                assert!(mapped.red >= previous - 1e-6, "{} is not monotonic", name);
+                assert!(mapped.red <= 1.0, "{} exceeds 1", name);
+                previous = mapped.red;
+            }
+        }
+    }
+}
@@ -55,6 +55,10 @@ impl Vector3 {
         D: Deserializer<'de>,
     {
         let v3 = Vector3::deserialize(deserializer)?;
+        //Leaving unit vectors alone means a saved scene reads back exactly as it was written.
+        if (v3.length() - 1.0).abs() < 1e-12 {
+            return Ok(v3);
+        }
         Ok(v3.normalise())
     }
 